@@ -1,4 +1,10 @@
-use near_jsonrpc_client::{errors::JsonRpcError, methods::health::RpcStatusError};
+use near_jsonrpc_client::{
+    errors::JsonRpcError,
+    methods::{
+        block::RpcBlockError, chunk::RpcChunkError, health::RpcStatusError,
+        EXPERIMENTAL_tx_status::RpcTransactionError,
+    },
+};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -6,7 +12,32 @@ pub enum Error {
     #[error("{:?}", _0)]
     JsonRpc(#[from] JsonRpcError<RpcStatusError>),
     #[error("{:?}", _0)]
+    JsonRpcBlock(#[from] JsonRpcError<RpcBlockError>),
+    #[error("{:?}", _0)]
+    JsonRpcChunk(#[from] JsonRpcError<RpcChunkError>),
+    #[error("{:?}", _0)]
+    JsonRpcTransaction(#[from] JsonRpcError<RpcTransactionError>),
+    #[error("{:?}", _0)]
+    Io(#[from] std::io::Error),
+    #[error("{:?}", _0)]
     Reqwest(#[from] reqwest::Error),
+    #[error("{:?}", _0)]
+    Sled(#[from] sled::Error),
+    #[error("Invalid value {value:?} of environment variable {name}")]
+    InvalidEnvVar { name: &'static str, value: String },
+    #[error("Could not find originating transaction of receipt {receipt_id} in block {block_height}, shard {shard_id}")]
+    UnresolvedReceipt {
+        block_height: u64,
+        shard_id: u64,
+        receipt_id: String,
+    },
+    #[error("Could not find originating transaction of data {data_id} of receipt {receipt_id} in block {block_height}, shard {shard_id}")]
+    UnresolvedData {
+        block_height: u64,
+        shard_id: u64,
+        receipt_id: String,
+        data_id: String,
+    },
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -13,11 +13,12 @@ use near_lake_framework::near_indexer_primitives::{
         ReceiptEnumView, ReceiptView, SignedTransactionView, StateChangeCauseView,
         StateChangeValueView, StateChangeWithCauseView,
     },
-    CryptoHash, IndexerChunkView,
+    CryptoHash, IndexerChunkView, IndexerExecutionOutcomeWithReceipt,
 };
-use near_primitives::account::AccessKeyPermission as NearAccessKeyPermission;
+use near_primitives::{account::AccessKeyPermission as NearAccessKeyPermission, hash::hash};
+use serde::Deserialize;
 use strum::{Display, EnumString};
-use util::get_action_type_and_value;
+use util::{get_action_type_and_value, get_failure_details, get_signed_delta};
 
 #[derive(GraphQLQuery)]
 #[graphql(
@@ -29,8 +30,10 @@ pub struct AddBlockData;
 
 pub use add_block_data::{
     AccessKey, Account, AccountChange, ActionReceipt, ActionReceiptAction, ActionReceiptInputData,
-    ActionReceiptOutputData, Block, BlockData, Chunk, DataReceipt, ExecutionOutcome,
-    ExecutionOutcomeReceipt, Receipt, Transaction, TransactionAction,
+    ActionReceiptOutputData, Block, BlockData, Chunk, Contract, ContractMetadata, DataReceipt,
+    Event, ExecutionOutcome, ExecutionOutcomeLog, ExecutionOutcomeReceipt, FtBalanceDelta,
+    FtTransfer, NativeTransfer, NftOwner, NftTransfer, Receipt, ReceiptTrace, Transaction,
+    TransactionAction, TransactionFee, TransactionSummary,
 };
 
 #[derive(GraphQLQuery)]
@@ -90,6 +93,25 @@ impl From<AccessKey> for GenesisAccessKey {
 )]
 pub struct DeleteAccounts;
 
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "src/graphql/schema.graphql",
+    query_path = "src/graphql/query.graphql",
+    response_derives = "Debug"
+)]
+pub struct UpdateReceiptLineages;
+
+pub use update_receipt_lineages::ReceiptLineage;
+
+impl update_receipt_lineages::ReceiptLineage {
+    pub fn new(receipt_id: CryptoHash, transaction_hash: CryptoHash) -> Self {
+        Self {
+            receipt_id: receipt_id.to_string(),
+            originated_from_transaction_hash: transaction_hash.to_string(),
+        }
+    }
+}
+
 impl add_block_data::Block {
     pub fn new(block_view: &BlockView, timestamp: i64) -> Self {
         Self {
@@ -139,7 +161,7 @@ impl add_block_data::Transaction {
             receiver_account_id: transaction.receiver_id.to_string(),
             signature: transaction.signature.to_string(),
             status: ExecutionOutcomeStatus::from(outcome.status.clone()).to_string(),
-            converted_into_receipt_id: outcome.receipt_ids.first().unwrap().to_string(),
+            converted_into_receipt_id: outcome.receipt_ids.first().map(CryptoHash::to_string),
             receipt_conversion_gas_burnt: outcome.gas_burnt.to_string(),
             receipt_conversion_tokens_burnt: outcome.tokens_burnt.to_string(),
         }
@@ -197,13 +219,16 @@ pub enum ActionKind {
 }
 
 impl add_block_data::Receipt {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         receipt: &ReceiptView,
         block_hash: CryptoHash,
         chunk_hash: CryptoHash,
         index_in_chunk: i64,
         timestamp: i64,
-        transaction_hash: CryptoHash,
+        transaction_hash: Option<CryptoHash>,
+        is_refund: bool,
+        refund_for_receipt_id: Option<CryptoHash>,
     ) -> Self {
         Self {
             receipt_id: receipt.receipt_id.to_string(),
@@ -217,7 +242,11 @@ impl add_block_data::Receipt {
                 ReceiptEnumView::Action { .. } => ReceiptKind::Action.to_string(),
                 ReceiptEnumView::Data { .. } => ReceiptKind::Data.to_string(),
             },
-            originated_from_transaction_hash: transaction_hash.to_string(),
+            originated_from_transaction_hash: transaction_hash
+                .map(|transaction_hash| transaction_hash.to_string()),
+            lineage_resolved: transaction_hash.is_some(),
+            is_refund,
+            refund_for_receipt_id: refund_for_receipt_id.map(|receipt_id| receipt_id.to_string()),
         }
     }
 }
@@ -261,6 +290,7 @@ impl add_block_data::ActionReceiptAction {
         index: i64,
         action_view: &ActionView,
         timestamp: i64,
+        is_refund: bool,
     ) -> Self {
         let (action_kind, args) = get_action_type_and_value(action_view);
         Self {
@@ -271,6 +301,7 @@ impl add_block_data::ActionReceiptAction {
             predecessor_id: receipt.predecessor_id.to_string(),
             receiver_id: receipt.receiver_id.to_string(),
             timestamp: timestamp.to_string(),
+            is_refund,
         }
     }
 }
@@ -296,15 +327,33 @@ impl add_block_data::ActionReceiptOutputData {
 
 impl add_block_data::ExecutionOutcome {
     pub fn new(
-        receipt: &ReceiptView,
+        receipt_id: Option<CryptoHash>,
+        transaction_hash: Option<CryptoHash>,
         block_hash: CryptoHash,
         chunk_index: i64,
         timestamp: i64,
         outcome: &ExecutionOutcomeView,
         shard_id: u64,
     ) -> Self {
+        let (failure_action_index, failure_kind, failure_message) =
+            if let ExecutionStatusView::Failure(err) = &outcome.status {
+                let (action_index, kind, message) = get_failure_details(err);
+                (action_index, Some(kind), Some(message))
+            } else {
+                (None, None, None)
+            };
+        let (return_value_base64, return_value_text) =
+            if let ExecutionStatusView::SuccessValue(value) = &outcome.status {
+                (
+                    Some(general_purpose::STANDARD.encode(value)),
+                    String::from_utf8(value.clone()).ok(),
+                )
+            } else {
+                (None, None)
+            };
         Self {
-            receipt_id: receipt.receipt_id.to_string(),
+            receipt_id: receipt_id.map(|receipt_id| receipt_id.to_string()),
+            transaction_hash: transaction_hash.map(|transaction_hash| transaction_hash.to_string()),
             block_hash: block_hash.to_string(),
             chunk_index,
             timestamp: timestamp.to_string(),
@@ -313,6 +362,11 @@ impl add_block_data::ExecutionOutcome {
             account_id: outcome.executor_id.to_string(),
             status: ExecutionOutcomeStatus::from(outcome.status.clone()).to_string(),
             shard: shard_id.to_string(),
+            failure_action_index,
+            failure_kind,
+            failure_message,
+            return_value_base64,
+            return_value_text,
         }
     }
 }
@@ -331,6 +385,288 @@ impl add_block_data::ExecutionOutcomeReceipt {
     }
 }
 
+impl add_block_data::ReceiptTrace {
+    pub fn new(
+        receipt_id: CryptoHash,
+        transaction_hash: CryptoHash,
+        parent_receipt_id: Option<CryptoHash>,
+        depth: i64,
+    ) -> Self {
+        Self {
+            receipt_id: receipt_id.to_string(),
+            transaction_hash: transaction_hash.to_string(),
+            parent_receipt_id: parent_receipt_id.map(|receipt_id| receipt_id.to_string()),
+            depth,
+        }
+    }
+}
+
+impl add_block_data::TransactionSummary {
+    pub fn new(
+        transaction_hash: CryptoHash,
+        total_receipts: i64,
+        total_gas_burnt: u64,
+        total_tokens_burnt: u128,
+        success: bool,
+        block_hash: CryptoHash,
+        block_height: u64,
+    ) -> Self {
+        Self {
+            transaction_hash: transaction_hash.to_string(),
+            total_receipts,
+            total_gas_burnt: total_gas_burnt.to_string(),
+            total_tokens_burnt: total_tokens_burnt.to_string(),
+            success,
+            completed_block_hash: block_hash.to_string(),
+            completed_block_height: block_height.to_string(),
+        }
+    }
+}
+
+impl add_block_data::TransactionFee {
+    /// The signer prepays gas and gets the unused part back as gas refunds, so the fee paid is
+    /// what the transaction and its receipts burnt.
+    pub fn new(
+        transaction_hash: CryptoHash,
+        signer_account_id: &AccountId,
+        conversion_tokens_burnt: u128,
+        total_tokens_burnt: u128,
+        gas_refunded: u128,
+        block_height: u64,
+    ) -> Self {
+        Self {
+            transaction_hash: transaction_hash.to_string(),
+            signer_account_id: signer_account_id.to_string(),
+            conversion_tokens_burnt: conversion_tokens_burnt.to_string(),
+            receipt_tokens_burnt: (total_tokens_burnt - conversion_tokens_burnt).to_string(),
+            fee_paid: total_tokens_burnt.to_string(),
+            gas_refunded: gas_refunded.to_string(),
+            completed_block_height: block_height.to_string(),
+        }
+    }
+}
+
+impl add_block_data::ExecutionOutcomeLog {
+    pub fn new(
+        receipt_id: Option<CryptoHash>,
+        transaction_hash: Option<CryptoHash>,
+        index_in_outcome: i64,
+        log: &str,
+        block_hash: CryptoHash,
+        timestamp: i64,
+        shard_id: u64,
+    ) -> Self {
+        Self {
+            receipt_id: receipt_id.map(|receipt_id| receipt_id.to_string()),
+            transaction_hash: transaction_hash.map(|transaction_hash| transaction_hash.to_string()),
+            index_in_outcome,
+            log: log.to_string(),
+            block_hash: block_hash.to_string(),
+            timestamp: timestamp.to_string(),
+            shard: shard_id.to_string(),
+        }
+    }
+}
+
+/// The NEP-297 envelope of an `EVENT_JSON:` log.
+#[derive(Debug, Deserialize)]
+pub struct EventLog {
+    pub standard: String,
+    pub version: String,
+    pub event: String,
+    #[serde(default)]
+    pub data: Option<serde_json::Value>,
+}
+
+impl add_block_data::Event {
+    pub fn new(
+        receipt_id: CryptoHash,
+        index_in_outcome: i64,
+        contract_account_id: &AccountId,
+        event_log: Result<&EventLog, String>,
+        block_hash: CryptoHash,
+        timestamp: i64,
+        shard_id: u64,
+    ) -> Self {
+        let (event_log, error) = match event_log {
+            Ok(event_log) => (Some(event_log), None),
+            Err(error) => (None, Some(error)),
+        };
+        Self {
+            receipt_id: receipt_id.to_string(),
+            index_in_outcome,
+            contract_account_id: contract_account_id.to_string(),
+            standard: event_log.map(|e| e.standard.clone()),
+            version: event_log.map(|e| e.version.clone()),
+            event: event_log.map(|e| e.event.clone()),
+            data: event_log
+                .and_then(|e| e.data.as_ref())
+                .map(|data| data.to_string()),
+            error,
+            block_hash: block_hash.to_string(),
+            timestamp: timestamp.to_string(),
+            shard: shard_id.to_string(),
+        }
+    }
+}
+
+impl add_block_data::NativeTransfer {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        outcome: &IndexerExecutionOutcomeWithReceipt,
+        index_in_receipt: i64,
+        from_account_id: &AccountId,
+        to_account_id: &AccountId,
+        amount: u128,
+        cause: NativeTransferCause,
+        block_hash: CryptoHash,
+        timestamp: i64,
+    ) -> Self {
+        Self {
+            receipt_id: outcome.receipt.receipt_id.to_string(),
+            index_in_receipt,
+            from_account_id: from_account_id.to_string(),
+            to_account_id: to_account_id.to_string(),
+            amount: amount.to_string(),
+            cause: cause.to_string(),
+            success: matches!(
+                outcome.execution_outcome.outcome.status,
+                ExecutionStatusView::SuccessValue(_) | ExecutionStatusView::SuccessReceiptId(_)
+            ),
+            block_hash: block_hash.to_string(),
+            timestamp: timestamp.to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Display, EnumString)]
+#[strum(serialize_all = "SCREAMING_SNAKE_CASE")]
+pub enum NativeTransferCause {
+    Transfer,
+    FunctionCallDeposit,
+    DeleteAccount,
+    GasRefund,
+    DepositRefund,
+    /// A transfer from `system` produced by a receipt that is not tracked, e.g. before a restart.
+    Unknown,
+}
+
+impl add_block_data::FtTransfer {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        outcome: &IndexerExecutionOutcomeWithReceipt,
+        index_in_receipt: i64,
+        cause: TokenTransferCause,
+        source: TransferSource,
+        from_account_id: Option<&str>,
+        to_account_id: Option<&str>,
+        amount: u128,
+        memo: Option<&str>,
+        block_hash: CryptoHash,
+        timestamp: i64,
+    ) -> Self {
+        Self {
+            receipt_id: outcome.receipt.receipt_id.to_string(),
+            index_in_receipt,
+            token_account_id: outcome.receipt.receiver_id.to_string(),
+            cause: cause.to_string(),
+            source: source.to_string(),
+            from_account_id: from_account_id.map(ToString::to_string),
+            to_account_id: to_account_id.map(ToString::to_string),
+            amount: amount.to_string(),
+            memo: memo.map(ToString::to_string),
+            success: matches!(
+                outcome.execution_outcome.outcome.status,
+                ExecutionStatusView::SuccessValue(_) | ExecutionStatusView::SuccessReceiptId(_)
+            ),
+            block_hash: block_hash.to_string(),
+            timestamp: timestamp.to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Display, EnumString)]
+#[strum(serialize_all = "SCREAMING_SNAKE_CASE")]
+pub enum TokenTransferCause {
+    Mint,
+    Transfer,
+    /// A legacy `ft_transfer_call`, part of the amount may be refunded by `ft_resolve_transfer`.
+    TransferCall,
+    Burn,
+}
+
+#[derive(Clone, Copy, Display, EnumString)]
+#[strum(serialize_all = "SCREAMING_SNAKE_CASE")]
+pub enum TransferSource {
+    Event,
+    FunctionCall,
+}
+
+impl add_block_data::FtBalanceDelta {
+    pub fn new(
+        token_account_id: &str,
+        account_id: &str,
+        credit: u128,
+        debit: u128,
+        block_hash: CryptoHash,
+        block_height: u64,
+    ) -> Self {
+        Self {
+            token_account_id: token_account_id.to_string(),
+            account_id: account_id.to_string(),
+            delta: get_signed_delta(credit, debit),
+            block_hash: block_hash.to_string(),
+            block_height: block_height.to_string(),
+        }
+    }
+}
+
+impl add_block_data::NftTransfer {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        outcome: &IndexerExecutionOutcomeWithReceipt,
+        index_in_receipt: i64,
+        token_id: &str,
+        cause: TokenTransferCause,
+        from_account_id: Option<&str>,
+        to_account_id: Option<&str>,
+        authorized_account_id: Option<&str>,
+        memo: Option<&str>,
+        block_hash: CryptoHash,
+        timestamp: i64,
+    ) -> Self {
+        Self {
+            receipt_id: outcome.receipt.receipt_id.to_string(),
+            index_in_receipt,
+            contract_account_id: outcome.receipt.receiver_id.to_string(),
+            token_id: token_id.to_string(),
+            cause: cause.to_string(),
+            from_account_id: from_account_id.map(ToString::to_string),
+            to_account_id: to_account_id.map(ToString::to_string),
+            authorized_account_id: authorized_account_id.map(ToString::to_string),
+            memo: memo.map(ToString::to_string),
+            success: matches!(
+                outcome.execution_outcome.outcome.status,
+                ExecutionStatusView::SuccessValue(_) | ExecutionStatusView::SuccessReceiptId(_)
+            ),
+            block_hash: block_hash.to_string(),
+            timestamp: timestamp.to_string(),
+        }
+    }
+}
+
+impl add_block_data::NftOwner {
+    pub fn new(nft_transfer: &NftTransfer, block_height: u64) -> Self {
+        Self {
+            contract_account_id: nft_transfer.contract_account_id.clone(),
+            token_id: nft_transfer.token_id.clone(),
+            owner_account_id: nft_transfer.to_account_id.clone(),
+            last_update_receipt_id: nft_transfer.receipt_id.clone(),
+            block_height: block_height.to_string(),
+        }
+    }
+}
+
 impl add_block_data::Account {
     pub fn new(
         account_id: &AccountId,
@@ -347,11 +683,16 @@ impl add_block_data::Account {
 }
 
 impl add_block_data::AccountChange {
+    /// `previous_balances` are the nonstaked balance, staked balance and storage usage the account
+    /// had before this change, if known. `is_stake` tells whether the receipt causing the change
+    /// has a `Stake` action.
     pub fn new(
         state_change_with_cause: &StateChangeWithCauseView,
         block_hash: CryptoHash,
         timestamp: i64,
         index_in_block: i64,
+        previous_balances: Option<(u128, u128, u64)>,
+        is_stake: bool,
     ) -> Option<Self> {
         let StateChangeWithCauseView { cause, value } = state_change_with_cause;
 
@@ -363,6 +704,9 @@ impl add_block_data::AccountChange {
             StateChangeValueView::AccountDeletion { account_id } => (account_id.to_string(), None),
             _ => return None,
         };
+        let (nonstaked_balance, staked_balance, storage_usage) = account
+            .map(|acc| (acc.amount, acc.locked, acc.storage_usage))
+            .unwrap_or_default();
 
         Some(Self {
             account_id,
@@ -374,42 +718,68 @@ impl add_block_data::AccountChange {
             } else {
                 None
             },
-            receipt_id: match cause {
-                StateChangeCauseView::ActionReceiptProcessingStarted { receipt_hash } => {
-                    Some(receipt_hash.to_string())
-                }
-                StateChangeCauseView::ActionReceiptGasReward { receipt_hash } => {
-                    Some(receipt_hash.to_string())
-                }
-                StateChangeCauseView::ReceiptProcessing { receipt_hash } => {
-                    Some(receipt_hash.to_string())
-                }
-                StateChangeCauseView::PostponedReceipt { receipt_hash } => {
-                    Some(receipt_hash.to_string())
-                }
-                _ => None,
-            },
+            receipt_id: get_cause_receipt_id(cause),
             update_reason: UpdateReason::from(cause).to_string(),
-            nonstaked_balance: if let Some(acc) = account {
-                acc.amount.to_string()
-            } else {
-                "0".to_string()
-            },
-            staked_balance: if let Some(acc) = account {
-                acc.locked.to_string()
-            } else {
-                "0".to_string()
-            },
-            storage_usage: if let Some(acc) = account {
-                acc.storage_usage.to_string()
-            } else {
-                "0".to_string()
-            },
+            nonstaked_balance: nonstaked_balance.to_string(),
+            staked_balance: staked_balance.to_string(),
+            storage_usage: storage_usage.to_string(),
+            nonstaked_balance_delta: previous_balances
+                .map(|(previous, _, _)| get_signed_delta(nonstaked_balance, previous)),
+            staked_balance_delta: previous_balances
+                .map(|(_, previous, _)| get_signed_delta(staked_balance, previous)),
+            storage_usage_delta: previous_balances
+                .map(|(_, _, previous)| get_signed_delta(storage_usage as u128, previous as u128)),
+            balance_change_cause: BalanceChangeCause::new(cause, is_stake).to_string(),
             index_in_block,
         })
     }
 }
 
+#[derive(Clone, Copy, Display, EnumString)]
+#[strum(serialize_all = "SCREAMING_SNAKE_CASE")]
+pub enum BalanceChangeCause {
+    /// The signer paying for the gas, and the deposits, of a transaction it submitted.
+    GasPurchase,
+    GasReward,
+    Transfer,
+    Staking,
+    Other,
+}
+
+impl BalanceChangeCause {
+    fn new(cause: &StateChangeCauseView, is_stake: bool) -> Self {
+        match cause {
+            StateChangeCauseView::TransactionProcessing { .. } => Self::GasPurchase,
+            StateChangeCauseView::ActionReceiptGasReward { .. } => Self::GasReward,
+            StateChangeCauseView::ValidatorAccountsUpdate => Self::Staking,
+            StateChangeCauseView::ActionReceiptProcessingStarted { .. }
+            | StateChangeCauseView::ReceiptProcessing { .. }
+            | StateChangeCauseView::PostponedReceipt { .. } => {
+                if is_stake {
+                    Self::Staking
+                } else {
+                    Self::Transfer
+                }
+            }
+            _ => Self::Other,
+        }
+    }
+}
+
+fn get_cause_receipt_id(cause: &StateChangeCauseView) -> Option<String> {
+    match cause {
+        StateChangeCauseView::ActionReceiptProcessingStarted { receipt_hash } => {
+            Some(receipt_hash.to_string())
+        }
+        StateChangeCauseView::ActionReceiptGasReward { receipt_hash } => {
+            Some(receipt_hash.to_string())
+        }
+        StateChangeCauseView::ReceiptProcessing { receipt_hash } => Some(receipt_hash.to_string()),
+        StateChangeCauseView::PostponedReceipt { receipt_hash } => Some(receipt_hash.to_string()),
+        _ => None,
+    }
+}
+
 #[derive(Display, EnumString)]
 #[strum(serialize_all = "SCREAMING_SNAKE_CASE")]
 pub enum UpdateReason {
@@ -446,6 +816,79 @@ impl From<&StateChangeCauseView> for UpdateReason {
     }
 }
 
+impl add_block_data::Contract {
+    /// The state change cause points at the receipt whose `DeployContract` action landed.
+    pub fn new(
+        state_change_with_cause: &StateChangeWithCauseView,
+        block_hash: CryptoHash,
+        block_height: u64,
+        timestamp: i64,
+        index_in_block: i64,
+    ) -> Option<Self> {
+        let StateChangeWithCauseView { cause, value } = state_change_with_cause;
+
+        let (account_id, code): (String, Option<&Vec<u8>>) = match value {
+            StateChangeValueView::ContractCodeUpdate { account_id, code } => {
+                (account_id.to_string(), Some(code))
+            }
+            StateChangeValueView::ContractCodeDeletion { account_id } => {
+                (account_id.to_string(), None)
+            }
+            _ => return None,
+        };
+
+        Some(Self {
+            account_id,
+            block_hash: block_hash.to_string(),
+            block_height: block_height.to_string(),
+            timestamp: timestamp.to_string(),
+            receipt_id: get_cause_receipt_id(cause),
+            code_hash: code.map(|code| hex::encode(hash(code))),
+            code_size: code.map(|code| code.len().to_string()),
+            index_in_block,
+        })
+    }
+}
+
+impl add_block_data::ContractMetadata {
+    pub fn new(
+        state_change_with_cause: &StateChangeWithCauseView,
+        block_height: u64,
+        exported_methods: Vec<String>,
+        imported_functions: Vec<String>,
+        standards: &[ContractStandard],
+        parse_error: Option<String>,
+    ) -> Option<Self> {
+        let StateChangeWithCauseView { cause, value } = state_change_with_cause;
+
+        let StateChangeValueView::ContractCodeUpdate { account_id, code } = value else {
+            return None;
+        };
+
+        Some(Self {
+            account_id: account_id.to_string(),
+            code_hash: hex::encode(hash(code)),
+            receipt_id: get_cause_receipt_id(cause),
+            block_height: block_height.to_string(),
+            exported_methods,
+            imported_functions,
+            standards: standards.iter().map(ToString::to_string).collect(),
+            parse_error,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Display, EnumString)]
+#[strum(serialize_all = "SCREAMING_SNAKE_CASE")]
+pub enum ContractStandard {
+    Nep141,
+    Nep145,
+    Nep148,
+    Nep171,
+    Nep177,
+    Nep330,
+}
+
 impl add_block_data::AccessKey {
     pub fn new(
         public_key: &PublicKey,
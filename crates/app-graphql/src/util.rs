@@ -1,5 +1,6 @@
 use base64::{engine::general_purpose, Engine as _};
 use near_lake_framework::near_indexer_primitives::views::ActionView;
+use near_primitives::errors::TxExecutionError;
 
 use crate::ActionKind;
 
@@ -8,13 +9,10 @@ pub(crate) fn get_action_type_and_value(
 ) -> (ActionKind, serde_json::Value) {
     match action_view {
         ActionView::CreateAccount => (ActionKind::CreateAccount, json!({})),
+        // The view already holds the SHA-256 of the deployed code rather than the code itself.
         ActionView::DeployContract { code } => (
             ActionKind::DeployContract,
-            json!({
-                "code_sha256":  hex::encode(
-                    general_purpose::STANDARD.decode(code).expect("code expected to be encoded to base64")
-                )
-            }),
+            json!({ "code_sha256": hex::encode(code) }),
         ),
         ActionView::FunctionCall {
             method_name,
@@ -82,6 +80,25 @@ pub(crate) fn get_action_type_and_value(
     }
 }
 
+/// Returns the failed action index, the error kind (e.g. `FunctionCallError`) and the message.
+pub(crate) fn get_failure_details(err: &TxExecutionError) -> (Option<i64>, String, String) {
+    let (action_index, kind) = match err {
+        TxExecutionError::ActionError(action_error) => (
+            action_error.index.map(|index| index as i64),
+            serde_json::to_value(&action_error.kind),
+        ),
+        TxExecutionError::InvalidTxError(invalid_tx_error) => {
+            (None, serde_json::to_value(invalid_tx_error))
+        }
+    };
+    let kind = match kind {
+        Ok(serde_json::Value::Object(object)) => object.keys().next().cloned(),
+        Ok(serde_json::Value::String(kind)) => Some(kind),
+        _ => None,
+    };
+    (action_index, kind.unwrap_or_default(), err.to_string())
+}
+
 pub(crate) fn escape_json(object: &mut serde_json::Value) {
     match object {
         serde_json::Value::Object(ref mut value) => {
@@ -98,3 +115,11 @@ pub(crate) fn escape_json(object: &mut serde_json::Value) {
         _ => {}
     }
 }
+
+pub(crate) fn get_signed_delta(current: u128, previous: u128) -> String {
+    if current >= previous {
+        (current - previous).to_string()
+    } else {
+        format!("-{}", previous - current)
+    }
+}
@@ -0,0 +1,375 @@
+use near_jsonrpc_client::{
+    methods::{self, block::RpcBlockError, EXPERIMENTAL_tx_status::TransactionInfo},
+    JsonRpcClient,
+};
+use near_jsonrpc_primitives::types::chunks::ChunkReference;
+use near_lake_framework::near_indexer_primitives::{
+    types::{BlockId, BlockReference},
+    CryptoHash,
+};
+use qlytics_core::{Error, Result};
+use qlytics_graphql::ReceiptLineage;
+use std::{
+    collections::{btree_map::Entry, BTreeMap, HashMap, HashSet},
+    env,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+};
+
+/// Appends receipts that left the lineage window unresolved to `UNRESOLVED_RECEIPTS_PATH`,
+/// one `<receipt_id> <block_height>` per line, for `backfill_lineage` to pick up later.
+pub(crate) fn store_unresolved_receipts(
+    unresolved_receipts: impl IntoIterator<Item = (CryptoHash, u64)>,
+) -> Result<()> {
+    let Ok(path) = env::var("UNRESOLVED_RECEIPTS_PATH") else {
+        return Ok(());
+    };
+    let mut lines = String::new();
+    for (receipt_id, block_height) in unresolved_receipts {
+        lines.push_str(&format!("{} {}\n", receipt_id, block_height));
+    }
+    if !lines.is_empty() {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?
+            .write_all(lines.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Resolves the receipts listed in `UNRESOLVED_RECEIPTS_PATH` via JSON-RPC.
+///
+/// Every receipt is searched for from the block it was seen in down to `BACKFILL_MAX_BLOCKS`
+/// blocks before it. Every transaction of every new chunk in that range is looked up with
+/// `EXPERIMENTAL_tx_status` and the receipts of its execution tree are matched. Chunks and
+/// transactions the node can not return, e.g. garbage collected ones, are skipped.
+pub async fn backfill_lineage() -> Result<Vec<ReceiptLineage>> {
+    let client = JsonRpcClient::connect(
+        env::var("RPC_URL").unwrap_or_else(|_| "https://archival-rpc.mainnet.near.org".to_string()),
+    );
+    let max_blocks = match env::var("BACKFILL_MAX_BLOCKS") {
+        Ok(value) => value.parse::<u64>().map_err(|_| Error::InvalidEnvVar {
+            name: "BACKFILL_MAX_BLOCKS",
+            value,
+        })?,
+        Err(_) => 100,
+    };
+
+    let mut unresolved_receipts = read_unresolved_receipts()?;
+    let mut receipt_lineages = vec![];
+    // Number of unresolved receipts per block height, to skip the blocks no receipt is searched in.
+    let mut unresolved_heights: BTreeMap<u64, usize> = BTreeMap::new();
+    for block_height in unresolved_receipts.values() {
+        *unresolved_heights.entry(*block_height).or_default() += 1;
+    }
+
+    let mut next_block_height = unresolved_heights.keys().next_back().copied();
+    while let Some(block_height) = next_block_height {
+        if unresolved_heights
+            .range(block_height..=block_height.saturating_add(max_blocks))
+            .next()
+            .is_none()
+        {
+            next_block_height = unresolved_heights
+                .range(..block_height)
+                .next_back()
+                .map(|(block_height, _)| *block_height);
+            continue;
+        }
+        next_block_height = block_height.checked_sub(1);
+
+        let block = match client
+            .call(methods::block::RpcBlockRequest {
+                block_reference: BlockReference::BlockId(BlockId::Height(block_height)),
+            })
+            .await
+        {
+            Ok(block) => block,
+            Err(err)
+                if matches!(
+                    err.handler_error(),
+                    Some(RpcBlockError::UnknownBlock { .. })
+                ) =>
+            {
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        for chunk_header in block
+            .chunks
+            .iter()
+            .filter(|chunk_header| chunk_header.height_included == block_height)
+        {
+            let chunk = match client
+                .call(methods::chunk::RpcChunkRequest {
+                    chunk_reference: ChunkReference::ChunkHash {
+                        chunk_id: chunk_header.chunk_hash,
+                    },
+                })
+                .await
+            {
+                Ok(chunk) => chunk,
+                Err(err) if err.handler_error().is_some() => {
+                    println!("Skipping chunk {}: {}", chunk_header.chunk_hash, err);
+                    continue;
+                }
+                Err(err) => return Err(err.into()),
+            };
+            for transaction in chunk.transactions {
+                let status = match client
+                    .call(
+                        methods::EXPERIMENTAL_tx_status::RpcTransactionStatusRequest {
+                            transaction_info: TransactionInfo::TransactionId {
+                                hash: transaction.hash,
+                                account_id: transaction.signer_id,
+                            },
+                        },
+                    )
+                    .await
+                {
+                    Ok(status) => status,
+                    Err(err) if err.handler_error().is_some() => {
+                        println!("Skipping transaction {}: {}", transaction.hash, err);
+                        continue;
+                    }
+                    Err(err) => return Err(err.into()),
+                };
+                let receipt_ids = status
+                    .final_outcome
+                    .receipts_outcome
+                    .iter()
+                    .map(|outcome| outcome.id)
+                    .chain(status.receipts.iter().map(|receipt| receipt.receipt_id));
+                for receipt_id in receipt_ids {
+                    let Some(receipt_block_height) = unresolved_receipts.remove(&receipt_id) else {
+                        continue;
+                    };
+                    receipt_lineages.push(ReceiptLineage::new(receipt_id, transaction.hash));
+                    if let Entry::Occupied(mut entry) =
+                        unresolved_heights.entry(receipt_block_height)
+                    {
+                        *entry.get_mut() -= 1;
+                        if *entry.get() == 0 {
+                            entry.remove();
+                        }
+                    }
+                }
+            }
+        }
+    }
+    Ok(receipt_lineages)
+}
+
+/// Rewrites `UNRESOLVED_RECEIPTS_PATH` without the receipts that have been resolved, so the next
+/// backfill does not search for them again.
+pub fn forget_resolved_receipts(resolved_receipt_ids: &[String]) -> Result<()> {
+    let path = env::var("UNRESOLVED_RECEIPTS_PATH")
+        .map_err(|err| io::Error::new(io::ErrorKind::NotFound, err))?;
+    let resolved_receipt_ids: HashSet<_> =
+        resolved_receipt_ids.iter().map(String::as_str).collect();
+    let lines: String = fs::read_to_string(&path)?
+        .lines()
+        .filter(|line| {
+            let receipt_id = line.split(' ').next().unwrap_or_default();
+            !line.trim().is_empty() && !resolved_receipt_ids.contains(receipt_id)
+        })
+        .map(|line| format!("{}\n", line))
+        .collect();
+    let tmp_path = format!("{}.tmp", path);
+    fs::write(&tmp_path, lines)?;
+    fs::rename(tmp_path, path)?;
+    Ok(())
+}
+
+fn read_unresolved_receipts() -> Result<HashMap<CryptoHash, u64>> {
+    let path = env::var("UNRESOLVED_RECEIPTS_PATH")
+        .map_err(|err| io::Error::new(io::ErrorKind::NotFound, err))?;
+    fs::read_to_string(path)?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let invalid_line = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid unresolved receipt line: {}", line),
+                )
+            };
+            let (receipt_id, block_height) = line.split_once(' ').ok_or_else(invalid_line)?;
+            Ok((
+                receipt_id.parse().map_err(|_| invalid_line())?,
+                block_height.trim().parse().map_err(|_| invalid_line())?,
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{
+        block_json, chunk_header_json, hash, outcome_json, receipt_json, transaction_json,
+    };
+    use serde_json::{json, Value};
+    use std::{
+        io::{BufRead, BufReader, Read},
+        net::TcpListener,
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    const TRANSACTION_BLOCK_HEIGHT: u64 = 1_000;
+
+    /// Answers JSON-RPC requests with `respond`, one request per connection.
+    fn serve_rpc(respond: impl Fn(&Value) -> Value + Send + 'static) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                let request: Value = serde_json::from_slice(&body).unwrap();
+                let mut response = respond(&request);
+                response["jsonrpc"] = json!("2.0");
+                response["id"] = request["id"].clone();
+                let response = response.to_string();
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    response.len(),
+                    response
+                )
+                .unwrap();
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn backfill_lineage_searches_each_receipt_window_only() {
+        let pruned_transaction_hash = hash("pruned transaction");
+        let transaction_hash = hash("transaction");
+        let receipt_id = hash("receipt");
+        let lost_receipt_id = hash("lost receipt");
+
+        let requested_block_heights = Arc::new(Mutex::new(vec![]));
+        let url = serve_rpc({
+            let requested_block_heights = requested_block_heights.clone();
+            move |request| match request["method"].as_str().unwrap() {
+                "block" => {
+                    let block_height = request["params"]["block_id"].as_u64().unwrap();
+                    requested_block_heights.lock().unwrap().push(block_height);
+                    if block_height == TRANSACTION_BLOCK_HEIGHT {
+                        json!({ "result": block_json(block_height, 1) })
+                    } else {
+                        json!({
+                            "error": {
+                                "name": "HANDLER_ERROR",
+                                "cause": {
+                                    "name": "UNKNOWN_BLOCK",
+                                    "info": { "error_message": "DB Not Found" },
+                                },
+                                "code": -32000,
+                                "message": "Server error",
+                            },
+                        })
+                    }
+                }
+                "chunk" => json!({
+                    "result": {
+                        "author": "validator.near",
+                        "header": chunk_header_json(TRANSACTION_BLOCK_HEIGHT, 0),
+                        "transactions": [
+                            transaction_json(pruned_transaction_hash, "carol.near"),
+                            transaction_json(transaction_hash, "alice.near"),
+                        ],
+                        "receipts": [],
+                    },
+                }),
+                "EXPERIMENTAL_tx_status"
+                    if request["params"][0] == json!(pruned_transaction_hash) =>
+                {
+                    json!({
+                        "error": {
+                            "name": "HANDLER_ERROR",
+                            "cause": {
+                                "name": "UNKNOWN_TRANSACTION",
+                                "info": { "requested_transaction_hash": pruned_transaction_hash },
+                            },
+                            "code": -32000,
+                            "message": "Server error",
+                        },
+                    })
+                }
+                "EXPERIMENTAL_tx_status" => json!({
+                    "result": {
+                        "status": { "SuccessValue": "" },
+                        "transaction": transaction_json(transaction_hash, "alice.near"),
+                        "transaction_outcome":
+                            outcome_json(transaction_hash, "alice.near", &[receipt_id]),
+                        "receipts_outcome": [outcome_json(receipt_id, "bob.near", &[])],
+                        "receipts": [
+                            receipt_json(receipt_id, "alice.near", "bob.near", json!([])),
+                        ],
+                    },
+                }),
+                method => panic!("unexpected method {}", method),
+            }
+        });
+
+        let path = env::temp_dir().join(format!("unresolved-receipts-{}", std::process::id()));
+        fs::write(
+            &path,
+            format!(
+                "{} {}\n{} 5000\n",
+                receipt_id,
+                TRANSACTION_BLOCK_HEIGHT + 10,
+                lost_receipt_id
+            ),
+        )
+        .unwrap();
+        env::set_var("RPC_URL", url);
+        env::set_var("UNRESOLVED_RECEIPTS_PATH", &path);
+        env::set_var("BACKFILL_MAX_BLOCKS", "20");
+
+        let receipt_lineages = backfill_lineage().await.unwrap();
+        assert_eq!(receipt_lineages.len(), 1);
+        assert_eq!(receipt_lineages[0].receipt_id, receipt_id.to_string());
+        assert_eq!(
+            receipt_lineages[0].originated_from_transaction_hash,
+            transaction_hash.to_string()
+        );
+
+        // The resolved receipt stops the search at its transaction, the lost one is searched for
+        // over its own window only.
+        let mut requested_block_heights = requested_block_heights.lock().unwrap().clone();
+        requested_block_heights.sort_unstable();
+        let expected_block_heights: Vec<_> = (TRANSACTION_BLOCK_HEIGHT
+            ..=TRANSACTION_BLOCK_HEIGHT + 10)
+            .chain(4_980..=5_000)
+            .collect();
+        assert_eq!(requested_block_heights, expected_block_heights);
+
+        forget_resolved_receipts(&[receipt_id.to_string()]).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{} 5000\n", lost_receipt_id)
+        );
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,182 @@
+use crate::lineage::parse_env_var;
+use near_lake_framework::near_indexer_primitives::types::AccountId;
+use qlytics_core::Result;
+use sled::{Db, Tree};
+use std::{collections::HashMap, mem};
+
+/// Maps accounts to their last known nonstaked balance, staked balance and storage usage, so that
+/// every account change can be reported as a delta.
+///
+/// Kept in memory for up to `ACCOUNT_BALANCES_MAX_ENTRIES` recently changed accounts by default.
+/// With `LINEAGE_STORE_PATH` set, the balances of every account are persisted next to the lineage
+/// mappings instead.
+pub(crate) struct BalanceStore {
+    backend: Backend,
+    evicted_balances: u64,
+}
+
+enum Backend {
+    Memory(RecentBalances),
+    Disk(Tree),
+}
+
+impl BalanceStore {
+    pub(crate) fn open(db: Option<&Db>) -> Result<Self> {
+        let backend = match db {
+            Some(db) => Backend::Disk(db.open_tree("account_balances")?),
+            None => Backend::Memory(RecentBalances::new(parse_env_var(
+                "ACCOUNT_BALANCES_MAX_ENTRIES",
+                1_000_000,
+            )?)),
+        };
+        Ok(Self {
+            backend,
+            evicted_balances: 0,
+        })
+    }
+
+    /// Number of account balances evicted so far.
+    pub(crate) fn evictions(&self) -> u64 {
+        self.evicted_balances
+    }
+
+    /// Stores the balances of an account, `None` once it is deleted, and returns the previous
+    /// ones if known.
+    pub(crate) fn replace_balances(
+        &mut self,
+        account_id: &AccountId,
+        balances: Option<(u128, u128, u64)>,
+    ) -> Result<Option<(u128, u128, u64)>> {
+        match &mut self.backend {
+            Backend::Memory(account_balances) => {
+                let (previous_balances, evicted) = account_balances.replace(account_id, balances);
+                self.evicted_balances += evicted;
+                Ok(previous_balances)
+            }
+            Backend::Disk(account_balances) => {
+                let previous_balances = match balances {
+                    Some(balances) => {
+                        account_balances.insert(account_id.as_bytes(), encode_balances(balances))?
+                    }
+                    None => account_balances.remove(account_id.as_bytes())?,
+                };
+                Ok(previous_balances.map(|value| decode_balances(&value)))
+            }
+        }
+    }
+}
+
+/// Balances of the recently changed accounts in two generations. Once the current one is full it
+/// replaces the previous one, dropping the accounts that did not change since.
+struct RecentBalances {
+    current: HashMap<AccountId, (u128, u128, u64)>,
+    previous: HashMap<AccountId, (u128, u128, u64)>,
+    max_entries: usize,
+}
+
+impl RecentBalances {
+    fn new(max_entries: usize) -> Self {
+        Self {
+            current: HashMap::new(),
+            previous: HashMap::new(),
+            max_entries,
+        }
+    }
+
+    /// Returns the previous balances and the number of dropped accounts.
+    fn replace(
+        &mut self,
+        account_id: &AccountId,
+        balances: Option<(u128, u128, u64)>,
+    ) -> (Option<(u128, u128, u64)>, u64) {
+        let previous_balances = self
+            .current
+            .remove(account_id)
+            .or_else(|| self.previous.remove(account_id));
+        let mut evicted = 0;
+        if let Some(balances) = balances {
+            if self.current.len() >= self.max_entries / 2 {
+                evicted = self.previous.len() as u64;
+                self.previous = mem::take(&mut self.current);
+            }
+            self.current.insert(account_id.clone(), balances);
+        }
+        (previous_balances, evicted)
+    }
+}
+
+fn encode_balances(
+    (nonstaked_balance, staked_balance, storage_usage): (u128, u128, u64),
+) -> Vec<u8> {
+    let mut value = nonstaked_balance.to_be_bytes().to_vec();
+    value.extend_from_slice(&staked_balance.to_be_bytes());
+    value.extend_from_slice(&storage_usage.to_be_bytes());
+    value
+}
+
+fn decode_balances(value: &[u8]) -> (u128, u128, u64) {
+    (
+        u128::from_be_bytes(value[..16].try_into().unwrap()),
+        u128::from_be_bytes(value[16..32].try_into().unwrap()),
+        u64::from_be_bytes(value[32..40].try_into().unwrap()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::open_sled;
+    use std::{env, fs};
+
+    #[test]
+    fn recent_balances_keep_recently_changed_accounts() {
+        let account_id = |i: u128| -> AccountId { format!("account{}.near", i).parse().unwrap() };
+        let mut balances = RecentBalances::new(4);
+        for i in 0..4 {
+            assert_eq!(balances.replace(&account_id(i), Some((i, 0, 0))), (None, 0));
+        }
+
+        // Account 0 moved to the current generation, account 1 is dropped with the previous one.
+        assert_eq!(
+            balances.replace(&account_id(0), Some((10, 0, 0))),
+            (Some((0, 0, 0)), 1)
+        );
+        assert_eq!(balances.replace(&account_id(1), Some((1, 0, 0))), (None, 0));
+        assert_eq!(balances.replace(&account_id(2), None), (Some((2, 0, 0)), 0));
+        assert_eq!(balances.replace(&account_id(2), None), (None, 0));
+        assert_eq!(balances.current.len() + balances.previous.len(), 3);
+    }
+
+    #[test]
+    fn balances_encoding_round_trips() {
+        let balances = (u128::MAX, 1, u64::MAX);
+        assert_eq!(decode_balances(&encode_balances(balances)), balances);
+    }
+
+    #[test]
+    fn disk_store_survives_reopening() {
+        let path = env::temp_dir().join(format!("balance-store-{}", std::process::id()));
+        let account_id: AccountId = "alice.near".parse().unwrap();
+        {
+            let db = open_sled(&path);
+            let mut balance_store = BalanceStore::open(Some(&db)).unwrap();
+            balance_store
+                .replace_balances(&account_id, Some((100, 0, 200)))
+                .unwrap();
+        }
+
+        let db = open_sled(&path);
+        let mut balance_store = BalanceStore::open(Some(&db)).unwrap();
+        assert_eq!(
+            balance_store.replace_balances(&account_id, None).unwrap(),
+            Some((100, 0, 200))
+        );
+        assert_eq!(
+            balance_store.replace_balances(&account_id, None).unwrap(),
+            None
+        );
+
+        drop((balance_store, db));
+        fs::remove_dir_all(path).unwrap();
+    }
+}
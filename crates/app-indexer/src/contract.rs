@@ -0,0 +1,242 @@
+use near_lake_framework::near_indexer_primitives::{
+    views::{StateChangeValueView, StateChangeWithCauseView},
+    CryptoHash,
+};
+use near_primitives::hash::hash;
+use qlytics_core::Result;
+use qlytics_graphql::{Contract, ContractMetadata, ContractStandard};
+use std::{
+    env, fs,
+    path::Path,
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use wasmparser::{BinaryReaderError, ExternalKind, Parser, Payload, TypeRef};
+
+const STANDARD_METHODS: &[(ContractStandard, &[&str])] = &[
+    (
+        ContractStandard::Nep141,
+        &[
+            "ft_transfer",
+            "ft_transfer_call",
+            "ft_total_supply",
+            "ft_balance_of",
+        ],
+    ),
+    (
+        ContractStandard::Nep145,
+        &[
+            "storage_deposit",
+            "storage_withdraw",
+            "storage_unregister",
+            "storage_balance_bounds",
+            "storage_balance_of",
+        ],
+    ),
+    (ContractStandard::Nep148, &["ft_metadata"]),
+    (
+        ContractStandard::Nep171,
+        &["nft_transfer", "nft_transfer_call", "nft_token"],
+    ),
+    (ContractStandard::Nep177, &["nft_metadata"]),
+    (ContractStandard::Nep330, &["contract_source_metadata"]),
+];
+
+pub fn handle_contracts(
+    state_changes: &[StateChangeWithCauseView],
+    block_hash: CryptoHash,
+    block_height: u64,
+    timestamp: i64,
+) -> Result<(Vec<Contract>, Vec<ContractMetadata>)> {
+    let code_dir = env::var("CONTRACT_CODE_DIR").ok();
+    let mut contracts = vec![];
+    let mut contract_metadatas = vec![];
+    for (index_in_block, state_change) in state_changes.iter().enumerate() {
+        if let StateChangeValueView::ContractCodeUpdate { code, .. } = &state_change.value {
+            if let Some(code_dir) = &code_dir {
+                store_code(code_dir, code)?;
+            }
+            let (exported_methods, imported_functions, parse_error) = match inspect_code(code) {
+                Ok((exported_methods, imported_functions)) => {
+                    (exported_methods, imported_functions, None)
+                }
+                Err(err) => (vec![], vec![], Some(err.to_string())),
+            };
+            let standards = match_standards(&exported_methods);
+            contract_metadatas.extend(ContractMetadata::new(
+                state_change,
+                block_height,
+                exported_methods,
+                imported_functions,
+                &standards,
+                parse_error,
+            ));
+        }
+        contracts.extend(Contract::new(
+            state_change,
+            block_hash,
+            block_height,
+            timestamp,
+            index_in_block as i64,
+        ));
+    }
+    Ok((contracts, contract_metadatas))
+}
+
+/// The standards whose methods are all exported.
+fn match_standards(exported_methods: &[String]) -> Vec<ContractStandard> {
+    STANDARD_METHODS
+        .iter()
+        .filter(|(_, methods)| {
+            methods
+                .iter()
+                .all(|method| exported_methods.iter().any(|m| m == method))
+        })
+        .map(|(standard, _)| *standard)
+        .collect()
+}
+
+/// Distinguishes the temporary files of concurrent writes, shards are handled in parallel and
+/// may deploy the same code in one block.
+static TMP_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Content-addressed: every distinct code is written once, under its hex encoded SHA-256 like
+/// `Contract.code_hash`. Written to a temporary file first, so a crash never leaves a truncated
+/// file behind under the final name.
+fn store_code(code_dir: &str, code: &[u8]) -> Result<()> {
+    let code_hash = hex::encode(hash(code));
+    let path = Path::new(code_dir).join(&code_hash);
+    if !path.exists() {
+        fs::create_dir_all(code_dir)?;
+        let tmp_path = Path::new(code_dir).join(format!(
+            "{}.{}.{}.tmp",
+            code_hash,
+            process::id(),
+            TMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&tmp_path, code)?;
+        fs::rename(tmp_path, path)?;
+    }
+    Ok(())
+}
+
+/// Returns the exported function names and the imported host functions of a WASM module.
+fn inspect_code(code: &[u8]) -> std::result::Result<(Vec<String>, Vec<String>), BinaryReaderError> {
+    let mut exported_methods = vec![];
+    let mut imported_functions = vec![];
+    for payload in Parser::new(0).parse_all(code) {
+        match payload? {
+            Payload::ExportSection(reader) => {
+                for export in reader {
+                    let export = export?;
+                    if export.kind == ExternalKind::Func {
+                        exported_methods.push(export.name.to_string());
+                    }
+                }
+            }
+            Payload::ImportSection(reader) => {
+                for import in reader {
+                    let import = import?;
+                    if let TypeRef::Func(_) = import.ty {
+                        imported_functions.push(format!("{}.{}", import.module, import.name));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok((exported_methods, imported_functions))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A module importing `imports` and exporting an empty function under each of `exports`.
+    fn wasm_module(imports: &[(&str, &str)], exports: &[&str]) -> Vec<u8> {
+        fn section(module: &mut Vec<u8>, id: u8, entries: &[Vec<u8>]) {
+            let mut content = vec![entries.len() as u8];
+            for entry in entries {
+                content.extend(entry);
+            }
+            module.push(id);
+            module.push(content.len() as u8);
+            module.extend(content);
+        }
+        fn name(name: &str) -> Vec<u8> {
+            let mut bytes = vec![name.len() as u8];
+            bytes.extend(name.as_bytes());
+            bytes
+        }
+
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+        section(&mut module, 1, &[vec![0x60, 0, 0]]);
+        let imports: Vec<_> = imports
+            .iter()
+            .map(|(module, field)| [name(module), name(field), vec![0, 0]].concat())
+            .collect();
+        section(&mut module, 2, &imports);
+        section(&mut module, 3, &vec![vec![0]; exports.len()]);
+        let exports: Vec<_> = exports
+            .iter()
+            .enumerate()
+            .map(|(index, export)| [name(export), vec![0, (imports.len() + index) as u8]].concat())
+            .collect();
+        section(&mut module, 7, &exports);
+        section(&mut module, 10, &vec![vec![2, 0, 0x0b]; exports.len()]);
+        module
+    }
+
+    #[test]
+    fn code_exports_and_imports_are_listed() {
+        let code = wasm_module(
+            &[("env", "log_utf8"), ("env", "storage_read")],
+            &["ft_transfer", "new"],
+        );
+        assert_eq!(
+            inspect_code(&code).unwrap(),
+            (
+                vec!["ft_transfer".to_string(), "new".to_string()],
+                vec!["env.log_utf8".to_string(), "env.storage_read".to_string()],
+            )
+        );
+        assert_eq!(
+            inspect_code(&wasm_module(&[], &[])).unwrap(),
+            (vec![], vec![])
+        );
+    }
+
+    #[test]
+    fn malformed_code_is_reported() {
+        assert!(inspect_code(b"not wasm").is_err());
+        let code = wasm_module(&[("env", "log_utf8")], &["ft_transfer"]);
+        assert!(inspect_code(&code[..code.len() - 4]).is_err());
+    }
+
+    #[test]
+    fn standards_need_all_their_methods() {
+        let standards = |methods: &[&str]| -> Vec<String> {
+            let methods: Vec<_> = methods.iter().map(ToString::to_string).collect();
+            match_standards(&methods)
+                .iter()
+                .map(ToString::to_string)
+                .collect()
+        };
+        assert_eq!(
+            standards(&[
+                "ft_transfer",
+                "ft_transfer_call",
+                "ft_total_supply",
+                "ft_balance_of",
+                "ft_metadata",
+                "nft_transfer",
+            ]),
+            ["NEP141", "NEP148"]
+        );
+        assert_eq!(
+            standards(&["ft_transfer", "ft_balance_of", "nft_metadata"]),
+            ["NEP177"]
+        );
+        assert!(standards(&[]).is_empty());
+    }
+}
@@ -0,0 +1,109 @@
+use near_lake_framework::near_indexer_primitives::{CryptoHash, IndexerShard};
+use qlytics_graphql::{Event, EventLog};
+use rayon::prelude::*;
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
+
+const EVENT_JSON_PREFIX: &str = "EVENT_JSON:";
+
+pub(crate) fn parse_event_log(log: &str) -> Option<Result<EventLog, String>> {
+    let event_log = log.strip_prefix(EVENT_JSON_PREFIX)?;
+    Some(serde_json::from_str(event_log.trim()).map_err(|err| err.to_string()))
+}
+
+pub(crate) fn handle_events(
+    shard: &IndexerShard,
+    block_hash: CryptoHash,
+    timestamp: i64,
+    malformed_events: &Arc<AtomicU32>,
+    keep_malformed_events: bool,
+) -> Vec<Event> {
+    shard
+        .receipt_execution_outcomes
+        .par_iter()
+        .flat_map_iter(|outcome| {
+            let outcome = &outcome.execution_outcome;
+            outcome
+                .outcome
+                .logs
+                .iter()
+                .enumerate()
+                .filter_map(|(index_in_outcome, log)| {
+                    let event_log = parse_event_log(log)?;
+                    if event_log.is_err() {
+                        malformed_events.fetch_add(1, Ordering::Relaxed);
+                        if !keep_malformed_events {
+                            return None;
+                        }
+                    }
+                    Some(Event::new(
+                        outcome.id,
+                        index_in_outcome as i64,
+                        &outcome.outcome.executor_id,
+                        event_log.as_ref().map_err(Clone::clone),
+                        block_hash,
+                        timestamp,
+                        shard.shard_id,
+                    ))
+                })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{hash, receipt, receipt_outcome, shard};
+
+    #[test]
+    fn event_logs_are_parsed() {
+        let event_log = parse_event_log(
+            r#"EVENT_JSON: {"standard":"nep171","version":"1.0.0","event":"nft_mint","data":[]}"#,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(event_log.standard, "nep171");
+        assert_eq!(event_log.version, "1.0.0");
+        assert_eq!(event_log.event, "nft_mint");
+        assert_eq!(event_log.data, Some(serde_json::json!([])));
+
+        let event_log =
+            parse_event_log(r#"EVENT_JSON:{"standard":"nep171","version":"1.0.0","event":"x"}"#)
+                .unwrap()
+                .unwrap();
+        assert_eq!(event_log.data, None);
+
+        assert!(parse_event_log("Transfer 10 from alice.near").is_none());
+        assert!(parse_event_log("EVENT_JSON:{not json").unwrap().is_err());
+        assert!(
+            parse_event_log(r#"EVENT_JSON:{"standard":"nep171","event":"nft_mint"}"#)
+                .unwrap()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn malformed_events_are_counted_and_kept_on_request() {
+        let mut outcome = receipt_outcome(receipt(hash("receipt"), "alice.near", "nft.near"), &[]);
+        outcome.execution_outcome.outcome.logs = vec![
+            r#"EVENT_JSON:{"standard":"nep171","version":"1.0.0","event":"nft_mint"}"#.to_string(),
+            "EVENT_JSON:{".to_string(),
+            "not an event".to_string(),
+        ];
+        let shard = shard(1, 0, vec![], vec![outcome]);
+        let malformed_events = Arc::new(AtomicU32::new(0));
+
+        let events = handle_events(&shard, hash("block"), 0, &malformed_events, false);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].error, None);
+        assert_eq!(malformed_events.load(Ordering::Relaxed), 1);
+
+        let events = handle_events(&shard, hash("block"), 0, &malformed_events, true);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].index_in_outcome, 1);
+        assert!(events[1].error.is_some());
+        assert_eq!(malformed_events.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,164 @@
+use near_lake_framework::near_indexer_primitives::{CryptoHash, IndexerShard};
+use qlytics_graphql::{ExecutionOutcome, ExecutionOutcomeLog, ExecutionOutcomeReceipt};
+use rayon::prelude::*;
+
+/// Transaction outcomes come first in the chunk, receipt outcomes are numbered after them.
+pub(crate) fn handle_execution_outcomes(
+    shard: &IndexerShard,
+    block_hash: CryptoHash,
+    timestamp: i64,
+) -> (Vec<ExecutionOutcome>, Vec<ExecutionOutcomeReceipt>) {
+    let transactions_len = shard
+        .chunk
+        .as_ref()
+        .map_or(0, |chunk| chunk.transactions.len());
+    let (execution_outcomes, execution_outcome_receipts): (Vec<_>, Vec<_>) = shard
+        .receipt_execution_outcomes
+        .par_iter()
+        .enumerate()
+        .map(|(index_in_chunk, outcome)| {
+            let execution_outcome = ExecutionOutcome::new(
+                Some(outcome.execution_outcome.id),
+                None,
+                block_hash,
+                (transactions_len + index_in_chunk) as i64,
+                timestamp,
+                &outcome.execution_outcome.outcome,
+                shard.shard_id,
+            );
+            let execution_outcome_receipts: Vec<_> = outcome
+                .execution_outcome
+                .outcome
+                .receipt_ids
+                .iter()
+                .enumerate()
+                .map(|(index, receipt_id)| {
+                    ExecutionOutcomeReceipt::new(
+                        outcome.execution_outcome.id,
+                        index as i64,
+                        *receipt_id,
+                    )
+                })
+                .collect();
+            (execution_outcome, execution_outcome_receipts)
+        })
+        .unzip();
+    let transaction_execution_outcomes = shard
+        .chunk
+        .iter()
+        .flat_map(|chunk| chunk.transactions.iter().enumerate())
+        .map(|(index_in_chunk, transaction)| {
+            ExecutionOutcome::new(
+                None,
+                Some(transaction.outcome.execution_outcome.id),
+                block_hash,
+                index_in_chunk as i64,
+                timestamp,
+                &transaction.outcome.execution_outcome.outcome,
+                shard.shard_id,
+            )
+        });
+    (
+        transaction_execution_outcomes
+            .chain(execution_outcomes)
+            .collect(),
+        execution_outcome_receipts.into_iter().flatten().collect(),
+    )
+}
+
+pub(crate) fn handle_execution_outcome_logs(
+    shard: &IndexerShard,
+    block_hash: CryptoHash,
+    timestamp: i64,
+) -> Vec<ExecutionOutcomeLog> {
+    let transaction_logs = shard
+        .chunk
+        .iter()
+        .flat_map(|chunk| &chunk.transactions)
+        .flat_map(|transaction| {
+            let outcome = &transaction.outcome.execution_outcome;
+            outcome
+                .outcome
+                .logs
+                .iter()
+                .enumerate()
+                .map(|(index_in_outcome, log)| {
+                    ExecutionOutcomeLog::new(
+                        None,
+                        Some(outcome.id),
+                        index_in_outcome as i64,
+                        log,
+                        block_hash,
+                        timestamp,
+                        shard.shard_id,
+                    )
+                })
+        });
+    let receipt_logs = shard
+        .receipt_execution_outcomes
+        .par_iter()
+        .flat_map_iter(|outcome| {
+            let outcome = &outcome.execution_outcome;
+            outcome
+                .outcome
+                .logs
+                .iter()
+                .enumerate()
+                .map(|(index_in_outcome, log)| {
+                    ExecutionOutcomeLog::new(
+                        Some(outcome.id),
+                        None,
+                        index_in_outcome as i64,
+                        log,
+                        block_hash,
+                        timestamp,
+                        shard.shard_id,
+                    )
+                })
+        })
+        .collect::<Vec<_>>();
+    transaction_logs.chain(receipt_logs).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{hash, receipt, receipt_outcome, shard, transaction};
+
+    #[test]
+    fn receipt_outcomes_follow_transaction_outcomes_in_chunk() {
+        let shard = shard(
+            1,
+            0,
+            vec![
+                transaction(hash("transaction 0"), "alice.near", hash("receipt 0")),
+                transaction(hash("transaction 1"), "alice.near", hash("receipt 1")),
+            ],
+            vec![
+                receipt_outcome(receipt(hash("receipt 2"), "alice.near", "bob.near"), &[]),
+                receipt_outcome(receipt(hash("receipt 3"), "alice.near", "bob.near"), &[]),
+            ],
+        );
+
+        let (execution_outcomes, _) = handle_execution_outcomes(&shard, hash("block"), 0);
+        let outcomes: Vec<_> = execution_outcomes
+            .into_iter()
+            .map(|outcome| {
+                (
+                    outcome.chunk_index,
+                    outcome.transaction_hash.is_some(),
+                    outcome.receipt_id.is_some(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            outcomes,
+            [
+                (0, true, false),
+                (1, true, false),
+                (2, false, true),
+                (3, false, true),
+            ]
+        );
+    }
+}
@@ -0,0 +1,221 @@
+//! Minimal chain data for the unit tests. Only the fields the indexer reads carry meaning, the
+//! rest are zeroed.
+
+use near_lake_framework::near_indexer_primitives::{
+    views::{BlockView, ReceiptView},
+    CryptoHash, IndexerChunkView, IndexerExecutionOutcomeWithReceipt, IndexerShard,
+    IndexerTransactionWithOutcome, StreamerMessage,
+};
+use serde_json::{json, Value};
+use std::{path::Path, thread, time::Duration};
+
+const PUBLIC_KEY: &str = "ed25519:11111111111111111111111111111111";
+const SIGNATURE: &str = "ed25519:1111111111111111111111111111111111111111111111111111111111111111";
+
+/// Opens the sled database at `path`. The background threads of a dropped handle may hold its lock
+/// for a moment longer, so opening is retried for up to a second.
+pub(crate) fn open_sled(path: &Path) -> sled::Db {
+    for _ in 0..100 {
+        if let Ok(db) = sled::open(path) {
+            return db;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    sled::open(path).unwrap()
+}
+
+pub(crate) fn hash(seed: &str) -> CryptoHash {
+    near_primitives::hash::hash(seed.as_bytes())
+}
+
+pub(crate) fn chunk_header_json(block_height: u64, shard_id: u64) -> Value {
+    json!({
+        "chunk_hash": hash(&format!("chunk {} {}", block_height, shard_id)),
+        "prev_block_hash": CryptoHash::default(),
+        "outcome_root": CryptoHash::default(),
+        "prev_state_root": CryptoHash::default(),
+        "encoded_merkle_root": CryptoHash::default(),
+        "encoded_length": 0,
+        "height_created": block_height,
+        "height_included": block_height,
+        "shard_id": shard_id,
+        "gas_used": 0,
+        "gas_limit": 0,
+        "rent_paid": "0",
+        "validator_reward": "0",
+        "balance_burnt": "0",
+        "outgoing_receipts_root": CryptoHash::default(),
+        "tx_root": CryptoHash::default(),
+        "validator_proposals": [],
+        "signature": SIGNATURE,
+    })
+}
+
+pub(crate) fn block_json(block_height: u64, shards: u64) -> Value {
+    json!({
+        "author": "validator.near",
+        "header": {
+            "height": block_height,
+            "prev_height": null,
+            "epoch_id": CryptoHash::default(),
+            "next_epoch_id": CryptoHash::default(),
+            "hash": hash(&format!("block {}", block_height)),
+            "prev_hash": hash(&format!("block {}", block_height - 1)),
+            "prev_state_root": CryptoHash::default(),
+            "chunk_receipts_root": CryptoHash::default(),
+            "chunk_headers_root": CryptoHash::default(),
+            "chunk_tx_root": CryptoHash::default(),
+            "outcome_root": CryptoHash::default(),
+            "chunks_included": shards,
+            "challenges_root": CryptoHash::default(),
+            "timestamp": 0,
+            "timestamp_nanosec": "0",
+            "random_value": CryptoHash::default(),
+            "validator_proposals": [],
+            "chunk_mask": [],
+            "gas_price": "0",
+            "block_ordinal": null,
+            "rent_paid": "0",
+            "validator_reward": "0",
+            "total_supply": "0",
+            "challenges_result": [],
+            "last_final_block": CryptoHash::default(),
+            "last_ds_final_block": CryptoHash::default(),
+            "next_bp_hash": CryptoHash::default(),
+            "block_merkle_root": CryptoHash::default(),
+            "epoch_sync_data_hash": null,
+            "approvals": [],
+            "signature": SIGNATURE,
+            "latest_protocol_version": 57,
+        },
+        "chunks": (0..shards)
+            .map(|shard_id| chunk_header_json(block_height, shard_id))
+            .collect::<Vec<_>>(),
+    })
+}
+
+pub(crate) fn transaction_json(transaction_hash: CryptoHash, signer_id: &str) -> Value {
+    json!({
+        "signer_id": signer_id,
+        "public_key": PUBLIC_KEY,
+        "nonce": 1,
+        "receiver_id": signer_id,
+        "actions": [],
+        "signature": SIGNATURE,
+        "hash": transaction_hash,
+    })
+}
+
+/// A successful outcome burning no gas.
+pub(crate) fn outcome_json(id: CryptoHash, executor_id: &str, receipt_ids: &[CryptoHash]) -> Value {
+    json!({
+        "proof": [],
+        "block_hash": CryptoHash::default(),
+        "id": id,
+        "outcome": {
+            "logs": [],
+            "receipt_ids": receipt_ids,
+            "gas_burnt": 0,
+            "tokens_burnt": "0",
+            "executor_id": executor_id,
+            "status": { "SuccessValue": "" },
+        },
+    })
+}
+
+pub(crate) fn receipt_json(
+    receipt_id: CryptoHash,
+    predecessor_id: &str,
+    receiver_id: &str,
+    actions: Value,
+) -> Value {
+    json!({
+        "predecessor_id": predecessor_id,
+        "receiver_id": receiver_id,
+        "receipt_id": receipt_id,
+        "receipt": {
+            "Action": {
+                "signer_id": predecessor_id,
+                "signer_public_key": PUBLIC_KEY,
+                "gas_price": "0",
+                "output_data_receivers": [],
+                "input_data_ids": [],
+                "actions": actions,
+            },
+        },
+    })
+}
+
+/// A transaction converted into `receipt_id`.
+pub(crate) fn transaction(
+    transaction_hash: CryptoHash,
+    signer_id: &str,
+    receipt_id: CryptoHash,
+) -> IndexerTransactionWithOutcome {
+    serde_json::from_value(json!({
+        "transaction": transaction_json(transaction_hash, signer_id),
+        "outcome": {
+            "execution_outcome": outcome_json(transaction_hash, signer_id, &[receipt_id]),
+            "receipt": null,
+        },
+    }))
+    .unwrap()
+}
+
+pub(crate) fn receipt(
+    receipt_id: CryptoHash,
+    predecessor_id: &str,
+    receiver_id: &str,
+) -> ReceiptView {
+    serde_json::from_value(receipt_json(
+        receipt_id,
+        predecessor_id,
+        receiver_id,
+        json!([]),
+    ))
+    .unwrap()
+}
+
+/// The execution of `receipt`, producing `receipt_ids`.
+pub(crate) fn receipt_outcome(
+    receipt: ReceiptView,
+    receipt_ids: &[CryptoHash],
+) -> IndexerExecutionOutcomeWithReceipt {
+    IndexerExecutionOutcomeWithReceipt {
+        execution_outcome: serde_json::from_value(outcome_json(
+            receipt.receipt_id,
+            receipt.receiver_id.as_str(),
+            receipt_ids,
+        ))
+        .unwrap(),
+        receipt,
+    }
+}
+
+pub(crate) fn shard(
+    block_height: u64,
+    shard_id: u64,
+    transactions: Vec<IndexerTransactionWithOutcome>,
+    receipt_execution_outcomes: Vec<IndexerExecutionOutcomeWithReceipt>,
+) -> IndexerShard {
+    IndexerShard {
+        shard_id,
+        chunk: Some(IndexerChunkView {
+            author: "validator.near".parse().unwrap(),
+            header: serde_json::from_value(chunk_header_json(block_height, shard_id)).unwrap(),
+            transactions,
+            receipts: receipt_execution_outcomes
+                .iter()
+                .map(|outcome| outcome.receipt.clone())
+                .collect(),
+        }),
+        receipt_execution_outcomes,
+        state_changes: vec![],
+    }
+}
+
+pub(crate) fn streamer_message(block_height: u64, shards: Vec<IndexerShard>) -> StreamerMessage {
+    let block: BlockView =
+        serde_json::from_value(block_json(block_height, shards.len() as u64)).unwrap();
+    StreamerMessage { block, shards }
+}
@@ -0,0 +1,384 @@
+use crate::event::parse_event_log;
+use near_lake_framework::near_indexer_primitives::{
+    views::{ActionView, ReceiptEnumView},
+    CryptoHash, IndexerExecutionOutcomeWithReceipt, IndexerShard,
+};
+use qlytics_graphql::{FtBalanceDelta, FtTransfer, TokenTransferCause, TransferSource};
+use rayon::prelude::*;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+const NEP141_STANDARD: &str = "nep141";
+
+#[derive(Deserialize)]
+struct FtMintOrBurnData {
+    owner_id: String,
+    amount: String,
+    memo: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct FtTransferData {
+    old_owner_id: String,
+    new_owner_id: String,
+    amount: String,
+    memo: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct FtTransferArgs {
+    receiver_id: String,
+    amount: String,
+    memo: Option<String>,
+}
+
+pub(crate) fn handle_ft_transfers(
+    shard: &IndexerShard,
+    block_hash: CryptoHash,
+    timestamp: i64,
+) -> Vec<FtTransfer> {
+    shard
+        .receipt_execution_outcomes
+        .par_iter()
+        .flat_map_iter(|outcome| {
+            let mut transfers = ft_transfers_from_events(outcome);
+            // Legacy tokens emit no events, fall back to the transfer calls themselves.
+            if transfers.is_empty() {
+                transfers = ft_transfers_from_function_calls(outcome);
+            }
+            transfers.into_iter().enumerate().map(
+                move |(index_in_receipt, (cause, source, from, to, amount, memo))| {
+                    FtTransfer::new(
+                        outcome,
+                        index_in_receipt as i64,
+                        cause,
+                        source,
+                        from.as_deref(),
+                        to.as_deref(),
+                        amount,
+                        memo.as_deref(),
+                        block_hash,
+                        timestamp,
+                    )
+                },
+            )
+        })
+        .collect()
+}
+
+pub(crate) fn handle_ft_balance_deltas(
+    ft_transfers: &[FtTransfer],
+    block_hash: CryptoHash,
+    block_height: u64,
+) -> Vec<FtBalanceDelta> {
+    // `None` once the sums overflow, amounts come from logs any contract can emit.
+    let mut deltas: HashMap<(&str, &str), Option<(u128, u128)>> = HashMap::new();
+    // Function call rows are unverified, the called contract may not even be a token.
+    let event_source = TransferSource::Event.to_string();
+    for transfer in ft_transfers
+        .iter()
+        .filter(|transfer| transfer.success && transfer.source == event_source)
+    {
+        let amount: u128 = transfer.amount.parse().unwrap();
+        if let Some(from) = &transfer.from_account_id {
+            let delta = deltas
+                .entry((&transfer.token_account_id, from))
+                .or_insert(Some((0, 0)));
+            *delta = delta.and_then(|(credit, debit)| Some((credit, debit.checked_add(amount)?)));
+        }
+        if let Some(to) = &transfer.to_account_id {
+            let delta = deltas
+                .entry((&transfer.token_account_id, to))
+                .or_insert(Some((0, 0)));
+            *delta = delta.and_then(|(credit, debit)| Some((credit.checked_add(amount)?, debit)));
+        }
+    }
+    deltas
+        .into_iter()
+        .filter_map(|(key, delta)| Some((key, delta?)))
+        .filter(|(_, (credit, debit))| credit != debit)
+        .map(|((token_account_id, account_id), (credit, debit))| {
+            FtBalanceDelta::new(
+                token_account_id,
+                account_id,
+                credit,
+                debit,
+                block_hash,
+                block_height,
+            )
+        })
+        .collect()
+}
+
+type FtTransferRow = (
+    TokenTransferCause,
+    TransferSource,
+    Option<String>,
+    Option<String>,
+    u128,
+    Option<String>,
+);
+
+fn ft_transfers_from_events(outcome: &IndexerExecutionOutcomeWithReceipt) -> Vec<FtTransferRow> {
+    outcome
+        .execution_outcome
+        .outcome
+        .logs
+        .iter()
+        .filter_map(|log| parse_event_log(log)?.ok())
+        .filter(|event_log| event_log.standard == NEP141_STANDARD)
+        .flat_map(|event_log| {
+            let data = event_log.data.unwrap_or_default();
+            let rows: Vec<_> = match event_log.event.as_str() {
+                "ft_mint" => serde_json::from_value::<Vec<FtMintOrBurnData>>(data)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|mint| {
+                        (
+                            TokenTransferCause::Mint,
+                            None,
+                            Some(mint.owner_id),
+                            mint.amount,
+                            mint.memo,
+                        )
+                    })
+                    .collect(),
+                "ft_transfer" => serde_json::from_value::<Vec<FtTransferData>>(data)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|transfer| {
+                        (
+                            TokenTransferCause::Transfer,
+                            Some(transfer.old_owner_id),
+                            Some(transfer.new_owner_id),
+                            transfer.amount,
+                            transfer.memo,
+                        )
+                    })
+                    .collect(),
+                "ft_burn" => serde_json::from_value::<Vec<FtMintOrBurnData>>(data)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|burn| {
+                        (
+                            TokenTransferCause::Burn,
+                            Some(burn.owner_id),
+                            None,
+                            burn.amount,
+                            burn.memo,
+                        )
+                    })
+                    .collect(),
+                _ => vec![],
+            };
+            rows.into_iter()
+                .filter_map(|(cause, from, to, amount, memo)| {
+                    Some((
+                        cause,
+                        TransferSource::Event,
+                        from,
+                        to,
+                        amount.parse().ok()?,
+                        memo,
+                    ))
+                })
+        })
+        .collect()
+}
+
+fn ft_transfers_from_function_calls(
+    outcome: &IndexerExecutionOutcomeWithReceipt,
+) -> Vec<FtTransferRow> {
+    let ReceiptEnumView::Action { actions, .. } = &outcome.receipt.receipt else {
+        return vec![];
+    };
+    actions
+        .iter()
+        .filter_map(|action| {
+            let ActionView::FunctionCall {
+                method_name, args, ..
+            } = action
+            else {
+                return None;
+            };
+            let cause = match method_name.as_str() {
+                "ft_transfer" => TokenTransferCause::Transfer,
+                "ft_transfer_call" => TokenTransferCause::TransferCall,
+                _ => return None,
+            };
+            let args: FtTransferArgs = serde_json::from_slice(args).ok()?;
+            Some((
+                cause,
+                TransferSource::FunctionCall,
+                Some(outcome.receipt.predecessor_id.to_string()),
+                Some(args.receiver_id),
+                args.amount.parse().ok()?,
+                args.memo,
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{hash, receipt, receipt_outcome, shard};
+    use serde_json::json;
+
+    fn token_outcome(
+        receipt_id: &str,
+        logs: &[serde_json::Value],
+        actions: Vec<ActionView>,
+    ) -> IndexerExecutionOutcomeWithReceipt {
+        let mut receipt = receipt(hash(receipt_id), "alice.near", "token.near");
+        if let ReceiptEnumView::Action {
+            actions: receipt_actions,
+            ..
+        } = &mut receipt.receipt
+        {
+            *receipt_actions = actions;
+        }
+        let mut outcome = receipt_outcome(receipt, &[]);
+        outcome.execution_outcome.outcome.logs = logs
+            .iter()
+            .map(|data| format!("EVENT_JSON:{data}"))
+            .collect();
+        outcome
+    }
+
+    fn ft_event(event: &str, data: serde_json::Value) -> serde_json::Value {
+        json!({ "standard": "nep141", "version": "1.0.0", "event": event, "data": data })
+    }
+
+    fn ft_transfers(outcomes: Vec<IndexerExecutionOutcomeWithReceipt>) -> Vec<FtTransfer> {
+        handle_ft_transfers(&shard(1, 0, vec![], outcomes), hash("block"), 0)
+    }
+
+    fn balance_deltas(ft_transfers: &[FtTransfer]) -> Vec<(String, String)> {
+        let mut deltas: Vec<_> = handle_ft_balance_deltas(ft_transfers, hash("block"), 1)
+            .into_iter()
+            .map(|delta| (delta.account_id, delta.delta))
+            .collect();
+        deltas.sort();
+        deltas
+    }
+
+    #[test]
+    fn events_and_legacy_calls_are_parsed() {
+        let transfers = ft_transfers(vec![
+            token_outcome(
+                "events",
+                &[
+                    ft_event(
+                        "ft_mint",
+                        json!([{ "owner_id": "alice.near", "amount": "100" }]),
+                    ),
+                    ft_event(
+                        "ft_transfer",
+                        json!([{
+                            "old_owner_id": "alice.near",
+                            "new_owner_id": "bob.near",
+                            "amount": "30",
+                            "memo": "rent",
+                        }]),
+                    ),
+                    ft_event(
+                        "ft_burn",
+                        json!([{ "owner_id": "bob.near", "amount": "10" }]),
+                    ),
+                    ft_event("ft_burn", json!([{ "owner_id": "bob.near" }])),
+                    ft_event(
+                        "ft_mint",
+                        json!([{ "owner_id": "bob.near", "amount": "-1" }]),
+                    ),
+                ],
+                vec![],
+            ),
+            token_outcome(
+                "legacy",
+                &[],
+                vec![
+                    ActionView::FunctionCall {
+                        method_name: "ft_transfer_call".to_string(),
+                        args: br#"{"receiver_id":"dex.near","amount":"5","msg":""}"#.to_vec(),
+                        gas: 0,
+                        deposit: 1,
+                    },
+                    ActionView::FunctionCall {
+                        method_name: "ft_balance_of".to_string(),
+                        args: br#"{"account_id":"alice.near"}"#.to_vec(),
+                        gas: 0,
+                        deposit: 0,
+                    },
+                ],
+            ),
+        ]);
+
+        let mut rows: Vec<_> = transfers
+            .iter()
+            .map(|transfer| {
+                (
+                    transfer.cause.as_str(),
+                    transfer.source.as_str(),
+                    transfer.from_account_id.as_deref(),
+                    transfer.to_account_id.as_deref(),
+                    transfer.amount.as_str(),
+                    transfer.memo.as_deref(),
+                )
+            })
+            .collect();
+        rows.sort();
+        assert_eq!(
+            rows,
+            [
+                ("BURN", "EVENT", Some("bob.near"), None, "10", None),
+                ("MINT", "EVENT", None, Some("alice.near"), "100", None),
+                (
+                    "TRANSFER",
+                    "EVENT",
+                    Some("alice.near"),
+                    Some("bob.near"),
+                    "30",
+                    Some("rent")
+                ),
+                (
+                    "TRANSFER_CALL",
+                    "FUNCTION_CALL",
+                    Some("alice.near"),
+                    Some("dex.near"),
+                    "5",
+                    None
+                ),
+            ]
+        );
+        assert_eq!(
+            balance_deltas(&transfers),
+            [
+                ("alice.near".to_string(), "70".to_string()),
+                ("bob.near".to_string(), "20".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn overflowing_balance_delta_is_dropped() {
+        let transfers = ft_transfers(vec![token_outcome(
+            "mints",
+            &[ft_event(
+                "ft_mint",
+                json!([
+                    { "owner_id": "alice.near", "amount": u128::MAX.to_string() },
+                    { "owner_id": "alice.near", "amount": "1" },
+                    { "owner_id": "bob.near", "amount": "1" },
+                ]),
+            )],
+            vec![],
+        )]);
+
+        assert_eq!(transfers.len(), 3);
+        assert_eq!(
+            balance_deltas(&transfers),
+            [("bob.near".to_string(), "1".to_string())]
+        );
+    }
+}
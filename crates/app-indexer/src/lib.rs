@@ -1,41 +1,69 @@
-#![feature(drain_filter)]
+#![feature(build_hasher_simple_hash_one, hash_drain_filter, hash_raw_entry)]
 
 mod account;
+mod backfill;
+mod balance;
+mod contract;
+mod event;
+mod execution_outcome;
+#[cfg(test)]
+mod fixtures;
+mod fungible_token;
 mod genesis;
+mod lineage;
 mod log;
+mod native_transfer;
+mod non_fungible_token;
 mod receipt;
 mod state_change;
+mod trace;
 mod transaction;
 
 use account::handle_accounts;
 use async_stream::try_stream;
+use backfill::store_unresolved_receipts;
+use balance::BalanceStore;
+use contract::handle_contracts;
 use either::Either;
+use event::handle_events;
+use execution_outcome::{handle_execution_outcome_logs, handle_execution_outcomes};
+use fungible_token::{handle_ft_balance_deltas, handle_ft_transfers};
 use futures_core::stream::Stream;
 use genesis::handle_genesis;
 use itertools::Itertools;
+use lineage::{open_db, LineageStore};
+use native_transfer::handle_native_transfers;
 use near_jsonrpc_client::JsonRpcClient;
 use near_lake_framework::{
-    near_indexer_primitives::{
-        types::AccountId, views::ReceiptEnumView, CryptoHash, StreamerMessage,
-    },
+    near_indexer_primitives::{types::AccountId, CryptoHash, StreamerMessage},
     LakeConfigBuilder,
 };
+use non_fungible_token::{handle_nft_owners, handle_nft_transfers};
 use parking_lot::RwLock;
 use qlytics_core::Result;
-use qlytics_graphql::{Block, BlockData, Chunk, GenesisBlockData};
+use qlytics_graphql::{Block, BlockData, Chunk, GenesisBlockData, ReceiptLineage};
 use rayon::prelude::*;
-use receipt::{handle_chunk_receipts, handle_shard_receipts};
+use receipt::{
+    check_lineage, handle_chunk_receipts, handle_shard_receipts, handle_unresolved_receipts,
+    resolve_block_lineage, resolve_local_receipts, resolve_refunds,
+};
 use state_change::handle_state_changes;
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
     env,
-    sync::Arc,
+    sync::{atomic::AtomicU32, Arc},
     time::{Duration, Instant},
 };
+use trace::TraceTracker;
 use transaction::handle_transactions;
 
-pub async fn start_indexing(
-) -> Result<impl Stream<Item = Result<Either<GenesisBlockData, (BlockData, Vec<AccountId>)>>>> {
+pub use backfill::{backfill_lineage, forget_resolved_receipts};
+
+pub async fn start_indexing() -> Result<
+    impl Stream<
+        Item = Result<Either<GenesisBlockData, (BlockData, Vec<AccountId>, Vec<ReceiptLineage>)>>,
+    >,
+> {
     let start_block_height = env::var("START_BLOCK_HEIGHT")
         .map(|s| s.parse::<u64>().unwrap_or_default())
         .unwrap_or_default();
@@ -65,10 +93,21 @@ pub async fn start_indexing(
 
     let time = Arc::new(RwLock::new(Instant::now()));
     let eta = Arc::new(RwLock::new(VecDeque::new()));
-    let receipt_id_to_tx_hash = Arc::new(RwLock::new(HashMap::new()));
-    let data_id_to_tx_hash = Arc::new(RwLock::new(HashMap::new()));
+    let db = open_db()?;
+    let lineage_store = Arc::new(RwLock::new(LineageStore::open(db.as_ref())?));
+    let balance_store = Arc::new(RwLock::new(BalanceStore::open(db.as_ref())?));
+    let unresolved_receipt_ids = Arc::new(RwLock::new(HashMap::new()));
+    let pending_local_receipt_ids = Arc::new(RwLock::new(HashSet::new()));
+    let trace_tracker = Arc::new(RwLock::new(TraceTracker::new()));
 
     let misses = Arc::new(RwLock::new(0));
+    let malformed_events = Arc::new(AtomicU32::new(0));
+    let keep_malformed_events = env::var("KEEP_MALFORMED_EVENTS")
+        .map(|keep| keep.parse::<bool>().unwrap())
+        .unwrap_or_default();
+    let strict_mode = env::var("STRICT_MODE")
+        .map(|strict| strict.parse::<bool>().unwrap())
+        .unwrap_or_default();
 
     Ok(try_stream! {
         if let Some(genesis_block_data) = genesis_block_data {
@@ -76,23 +115,36 @@ pub async fn start_indexing(
         }
 
         while let Some(msg) = stream.recv().await {
+            let block_height = msg.block.header.height;
             let block_data = handle_streamer_message(
                 client.clone(),
                 msg,
                 time.clone(),
                 eta.clone(),
-                receipt_id_to_tx_hash.clone(),
-                data_id_to_tx_hash.clone(),
+                lineage_store.clone(),
+                balance_store.clone(),
+                unresolved_receipt_ids.clone(),
+                pending_local_receipt_ids.clone(),
+                trace_tracker.clone(),
                 misses.clone(),
+                malformed_events.clone(),
+                keep_malformed_events,
+                strict_mode,
             )
             .await?;
 
             yield Either::Right(block_data);
 
-            receipt_id_to_tx_hash.write().retain(|_, (_, idx)| {
-                *idx += 1;
-                *idx < 15
-            });
+            lineage_store.write().evict(block_height)?;
+            let evicted_receipt_ids: Vec<_> = unresolved_receipt_ids
+                .write()
+                .drain_filter(|_, (_, idx)| {
+                    *idx += 1;
+                    *idx >= 15
+                })
+                .map(|(receipt_id, (block_height, _))| (receipt_id, block_height))
+                .collect();
+            store_unresolved_receipts(evicted_receipt_ids)?;
         }
     })
 }
@@ -103,73 +155,44 @@ async fn handle_streamer_message(
     msg: StreamerMessage,
     time: Arc<RwLock<Instant>>,
     eta: Arc<RwLock<VecDeque<(Duration, u64)>>>,
-    receipt_id_to_tx_hash: Arc<RwLock<HashMap<CryptoHash, (CryptoHash, u8)>>>,
-    data_id_to_tx_hash: Arc<RwLock<HashMap<CryptoHash, CryptoHash>>>,
+    lineage_store: Arc<RwLock<LineageStore>>,
+    balance_store: Arc<RwLock<BalanceStore>>,
+    unresolved_receipt_ids: Arc<RwLock<HashMap<CryptoHash, (u64, u8)>>>,
+    pending_local_receipt_ids: Arc<RwLock<HashSet<CryptoHash>>>,
+    trace_tracker: Arc<RwLock<TraceTracker>>,
     misses: Arc<RwLock<u32>>,
-) -> Result<(BlockData, Vec<AccountId>)> {
-    log::log(msg.block.header.height, &client, &time, &eta, &misses).await?;
+    malformed_events: Arc<AtomicU32>,
+    keep_malformed_events: bool,
+    strict_mode: bool,
+) -> Result<(BlockData, Vec<AccountId>, Vec<ReceiptLineage>)> {
+    log::log(
+        msg.block.header.height,
+        &client,
+        &time,
+        &eta,
+        &misses,
+        &malformed_events,
+        &lineage_store,
+        &balance_store,
+        &trace_tracker,
+    )
+    .await?;
 
     let block_hash = msg.block.header.hash;
+    let block_height = msg.block.header.height;
     let timestamp = msg.block.header.timestamp_nanosec as i64 / 1_000_000;
 
     let block = Block::new(&msg.block, timestamp);
 
-    msg.shards
-        .par_iter()
-        .filter_map(|shard| {
-            shard.receipt_execution_outcomes.iter().for_each(|outcome| {
-                outcome
-                    .execution_outcome
-                    .outcome
-                    .receipt_ids
-                    .iter()
-                    .for_each(|receipt_id| {
-                        let mut receipt_id_to_tx_hash = receipt_id_to_tx_hash.write();
-                        if let Some(hash) = receipt_id_to_tx_hash
-                            .get(&outcome.execution_outcome.id)
-                            .cloned()
-                        {
-                            receipt_id_to_tx_hash.insert(*receipt_id, hash);
-                        } else {
-                            // eprintln!("Could not find parent for receipt {}", receipt_id);
-                            // TODO strict mode
-                        }
-                    });
-            });
-            shard.chunk.as_ref()
-        })
-        .for_each(|chunk| {
-            chunk.transactions.par_iter().for_each(|transaction| {
-                if let Some(receipt) = &transaction.outcome.receipt {
-                    receipt_id_to_tx_hash
-                        .write()
-                        .insert(receipt.receipt_id, (transaction.transaction.hash, 0));
-                }
-                transaction
-                    .outcome
-                    .execution_outcome
-                    .outcome
-                    .receipt_ids
-                    .iter()
-                    .for_each(|receipt_id| {
-                        receipt_id_to_tx_hash
-                            .write()
-                            .insert(*receipt_id, (transaction.transaction.hash, 0));
-                    });
-            });
-            chunk.receipts.iter().for_each(|receipt| {
-                if let ReceiptEnumView::Data { data_id, .. } = receipt.receipt {
-                    let removed_data_id = data_id_to_tx_hash.write().remove(&data_id);
-                    if let Some(tx_hash) = removed_data_id {
-                        receipt_id_to_tx_hash
-                            .write()
-                            .insert(receipt.receipt_id, (tx_hash, 0));
-                    } else {
-                        // TODO strict mode
-                    }
-                }
-            });
-        });
+    let local_receipts = resolve_local_receipts(&msg, &mut pending_local_receipt_ids.write());
+    let receipt_id_to_tx_hash = resolve_block_lineage(
+        &msg,
+        &local_receipts,
+        &mut lineage_store.write(),
+        &unresolved_receipt_ids,
+        &misses,
+    )?;
+    let refunds = resolve_refunds(&msg, &local_receipts, &trace_tracker.read());
 
     #[allow(clippy::type_complexity)]
     let (
@@ -182,9 +205,6 @@ async fn handle_streamer_message(
         action_receipt_actions,
         action_receipt_input_datas,
         action_receipt_output_datas,
-        execution_outcomes,
-        execution_outcome_receipts,
-        account_changes,
     ): (
         Vec<_>,
         Vec<_>,
@@ -195,19 +215,11 @@ async fn handle_streamer_message(
         Vec<_>,
         Vec<_>,
         Vec<_>,
-        Vec<_>,
-        Vec<_>,
-        Vec<_>,
     ) = msg
         .shards
         .par_iter()
-        .filter_map(|shard| {
-            let chunk_view = if let Some(chunk) = &shard.chunk {
-                chunk
-            } else {
-                return None;
-            };
-
+        .filter_map(|shard| shard.chunk.as_ref().map(|chunk_view| (shard, chunk_view)))
+        .map(|(shard, chunk_view)| {
             let chunk = Chunk::new(chunk_view, block_hash);
 
             let chunk_hash = chunk_view.header.chunk_hash;
@@ -219,25 +231,21 @@ async fn handle_streamer_message(
                 action_receipt_actions,
                 action_receipt_input_datas,
                 action_receipt_output_datas,
-                execution_outcomes,
-                execution_outcome_receipts,
             ) = handle_chunk_receipts(
                 shard,
                 chunk_view,
                 block_hash,
                 chunk_hash,
                 timestamp,
+                &local_receipts,
                 &receipt_id_to_tx_hash,
-                &data_id_to_tx_hash,
-                &misses,
+                &refunds,
             );
 
             let (transactions, transaction_actions) =
                 handle_transactions(chunk_view, chunk_hash, block_hash, timestamp);
 
-            let account_changes = handle_state_changes(&shard.state_changes, block_hash, timestamp);
-
-            Some((
+            (
                 chunk,
                 transactions,
                 transaction_actions,
@@ -247,26 +255,88 @@ async fn handle_streamer_message(
                 action_receipt_actions,
                 action_receipt_input_datas,
                 action_receipt_output_datas,
-                execution_outcomes,
-                execution_outcome_receipts,
-                account_changes,
-            ))
+            )
         })
         .collect::<Vec<_>>()
         .into_iter()
         .multiunzip();
 
-    handle_shard_receipts(&msg, &receipt_id_to_tx_hash);
+    handle_shard_receipts(&msg, &lineage_store)?;
+    let receipt_lineages = handle_unresolved_receipts(&lineage_store, &unresolved_receipt_ids)?;
+    if strict_mode {
+        check_lineage(&msg, &local_receipts, &unresolved_receipt_ids)?;
+    }
+
+    let account_changes = handle_state_changes(&msg, block_hash, timestamp, &balance_store)?;
 
     let (accounts, account_ids, access_keys): (Vec<_>, Vec<_>, Vec<_>) = msg
         .shards
         .par_iter()
-        .map(|shard| handle_accounts(&shard.receipt_execution_outcomes, msg.block.header.height))
+        .map(|shard| handle_accounts(&shard.receipt_execution_outcomes, block_height))
         .flatten()
         .collect::<Vec<_>>()
         .into_iter()
         .multiunzip();
 
+    let (contracts, contract_metadatas): (Vec<_>, Vec<_>) = msg
+        .shards
+        .par_iter()
+        .map(|shard| handle_contracts(&shard.state_changes, block_hash, block_height, timestamp))
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .unzip();
+
+    let (execution_outcomes, execution_outcome_receipts): (Vec<_>, Vec<_>) = msg
+        .shards
+        .par_iter()
+        .map(|shard| handle_execution_outcomes(shard, block_hash, timestamp))
+        .collect::<Vec<_>>()
+        .into_iter()
+        .unzip();
+
+    let native_transfers =
+        handle_native_transfers(&msg, block_hash, timestamp, &trace_tracker.read());
+    let (receipt_traces, transaction_summaries, transaction_fees) =
+        trace_tracker.write().handle_block(&msg);
+
+    let execution_outcome_logs: Vec<_> = msg
+        .shards
+        .par_iter()
+        .map(|shard| handle_execution_outcome_logs(shard, block_hash, timestamp))
+        .flatten()
+        .collect();
+
+    let events: Vec<_> = msg
+        .shards
+        .par_iter()
+        .map(|shard| {
+            handle_events(
+                shard,
+                block_hash,
+                timestamp,
+                &malformed_events,
+                keep_malformed_events,
+            )
+        })
+        .flatten()
+        .collect();
+
+    let ft_transfers: Vec<_> = msg
+        .shards
+        .par_iter()
+        .map(|shard| handle_ft_transfers(shard, block_hash, timestamp))
+        .flatten()
+        .collect();
+    let ft_balance_deltas = handle_ft_balance_deltas(&ft_transfers, block_hash, block_height);
+
+    let nft_transfers: Vec<_> = msg
+        .shards
+        .par_iter()
+        .map(|shard| handle_nft_transfers(shard, block_hash, timestamp))
+        .flatten()
+        .collect();
+    let nft_owners = handle_nft_owners(&nft_transfers, block_height);
+
     Ok((
         BlockData {
             block,
@@ -284,10 +354,23 @@ async fn handle_streamer_message(
                 .collect(),
             execution_outcomes: execution_outcomes.into_iter().flatten().collect(),
             execution_outcome_receipts: execution_outcome_receipts.into_iter().flatten().collect(),
+            receipt_traces,
+            transaction_summaries,
+            transaction_fees,
+            execution_outcome_logs,
+            events,
+            native_transfers,
+            ft_transfers,
+            ft_balance_deltas,
+            nft_transfers,
+            nft_owners,
             accounts: accounts.into_iter().flatten().collect(),
-            account_changes: account_changes.into_iter().flatten().collect(),
+            account_changes,
             access_keys: access_keys.into_iter().flatten().collect(),
+            contracts: contracts.into_iter().flatten().collect(),
+            contract_metadatas: contract_metadatas.into_iter().flatten().collect(),
         },
         account_ids.into_iter().flatten().collect(),
+        receipt_lineages,
     ))
 }
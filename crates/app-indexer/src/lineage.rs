@@ -0,0 +1,478 @@
+use near_lake_framework::near_indexer_primitives::CryptoHash;
+use qlytics_core::{Error, Result};
+use sled::{Db, Tree};
+use std::{
+    collections::{hash_map::RandomState, HashMap, VecDeque},
+    env,
+    hash::BuildHasher,
+    str::FromStr,
+};
+
+const RECEIPT_KEY_KIND: u8 = 0;
+const DATA_KEY_KIND: u8 = 1;
+const MAX_AGE: usize = 15;
+
+/// Maps receipt ids and data ids to the hash of the transaction they originate from.
+///
+/// Kept in memory for the last 15 blocks by default, each map capped at `LINEAGE_MAX_ENTRIES`
+/// entries. With `LINEAGE_STORE_PATH` set, the mappings are persisted in an embedded key-value
+/// store instead, so they survive restarts, and kept for `LINEAGE_RETENTION_BLOCKS` blocks.
+pub(crate) struct LineageStore {
+    backend: Backend,
+    evicted_receipts: u64,
+    evicted_data: u64,
+}
+
+enum Backend {
+    Memory {
+        receipt_id_to_tx_hash: Generations,
+        data_id_to_tx_hash: Generations,
+        max_entries: usize,
+    },
+    Disk {
+        receipt_id_to_tx_hash: Tree,
+        data_id_to_tx_hash: Tree,
+        /// Keyed by block height, kind and id, so that expired entries can be evicted in order.
+        block_height_index: Tree,
+        retention_blocks: u64,
+    },
+}
+
+/// The embedded key-value store at `LINEAGE_STORE_PATH`, shared by the lineage and balance
+/// stores. `None` when they are kept in memory.
+pub(crate) fn open_db() -> Result<Option<Db>> {
+    match env::var("LINEAGE_STORE_PATH") {
+        Ok(path) => Ok(Some(sled::open(path)?)),
+        Err(_) => Ok(None),
+    }
+}
+
+impl LineageStore {
+    pub(crate) fn open(db: Option<&Db>) -> Result<Self> {
+        if let Some(db) = db {
+            return Self::open_disk(db, parse_env_var("LINEAGE_RETENTION_BLOCKS", 100_000)?);
+        }
+        Ok(Self::new(Backend::Memory {
+            receipt_id_to_tx_hash: Generations::new(),
+            data_id_to_tx_hash: Generations::new(),
+            max_entries: parse_env_var("LINEAGE_MAX_ENTRIES", 1_000_000)?,
+        }))
+    }
+
+    fn open_disk(db: &Db, retention_blocks: u64) -> Result<Self> {
+        Ok(Self::new(Backend::Disk {
+            receipt_id_to_tx_hash: db.open_tree("receipt_id_to_tx_hash")?,
+            data_id_to_tx_hash: db.open_tree("data_id_to_tx_hash")?,
+            block_height_index: db.open_tree("block_height_index")?,
+            retention_blocks,
+        }))
+    }
+
+    fn new(backend: Backend) -> Self {
+        Self {
+            backend,
+            evicted_receipts: 0,
+            evicted_data: 0,
+        }
+    }
+
+    /// Number of receipt and data id mappings evicted so far.
+    pub(crate) fn evictions(&self) -> (u64, u64) {
+        (self.evicted_receipts, self.evicted_data)
+    }
+
+    pub(crate) fn get_receipt(&self, receipt_id: &CryptoHash) -> Result<Option<CryptoHash>> {
+        match &self.backend {
+            Backend::Memory {
+                receipt_id_to_tx_hash,
+                ..
+            } => Ok(receipt_id_to_tx_hash.get(receipt_id)),
+            Backend::Disk {
+                receipt_id_to_tx_hash,
+                ..
+            } => Ok(receipt_id_to_tx_hash
+                .get(receipt_id)?
+                .map(|value| decode_tx_hash(&value))),
+        }
+    }
+
+    pub(crate) fn insert_receipt(
+        &mut self,
+        receipt_id: CryptoHash,
+        tx_hash: CryptoHash,
+        block_height: u64,
+    ) -> Result<()> {
+        match &mut self.backend {
+            Backend::Memory {
+                receipt_id_to_tx_hash,
+                ..
+            } => {
+                receipt_id_to_tx_hash.insert(receipt_id, tx_hash);
+            }
+            Backend::Disk {
+                receipt_id_to_tx_hash,
+                block_height_index,
+                ..
+            } => {
+                receipt_id_to_tx_hash.insert(receipt_id, tx_hash.as_ref())?;
+                block_height_index
+                    .insert(index_key(block_height, RECEIPT_KEY_KIND, &receipt_id), &[])?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn insert_data(
+        &mut self,
+        data_id: CryptoHash,
+        tx_hash: CryptoHash,
+        block_height: u64,
+    ) -> Result<()> {
+        match &mut self.backend {
+            Backend::Memory {
+                data_id_to_tx_hash, ..
+            } => {
+                data_id_to_tx_hash.insert(data_id, tx_hash);
+            }
+            Backend::Disk {
+                data_id_to_tx_hash,
+                block_height_index,
+                ..
+            } => {
+                data_id_to_tx_hash.insert(data_id, tx_hash.as_ref())?;
+                block_height_index.insert(index_key(block_height, DATA_KEY_KIND, &data_id), &[])?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn remove_data(&mut self, data_id: &CryptoHash) -> Result<Option<CryptoHash>> {
+        match &mut self.backend {
+            Backend::Memory {
+                data_id_to_tx_hash, ..
+            } => Ok(data_id_to_tx_hash.remove(data_id)),
+            Backend::Disk {
+                data_id_to_tx_hash, ..
+            } => Ok(data_id_to_tx_hash
+                .remove(data_id)?
+                .map(|value| decode_tx_hash(&value))),
+        }
+    }
+
+    /// Called once per indexed block, drops the mappings that are out of retention.
+    pub(crate) fn evict(&mut self, block_height: u64) -> Result<()> {
+        match &mut self.backend {
+            Backend::Memory {
+                receipt_id_to_tx_hash,
+                data_id_to_tx_hash,
+                max_entries,
+                ..
+            } => {
+                self.evicted_receipts += receipt_id_to_tx_hash.rotate(*max_entries);
+                self.evicted_data += data_id_to_tx_hash.rotate(*max_entries);
+            }
+            Backend::Disk {
+                receipt_id_to_tx_hash,
+                data_id_to_tx_hash,
+                block_height_index,
+                retention_blocks,
+                ..
+            } => {
+                let retained_from = block_height.saturating_sub(*retention_blocks);
+                for key in block_height_index.range(..retained_from.to_be_bytes()) {
+                    let (key, _) = key?;
+                    let id = &key[9..];
+                    if key[8] == RECEIPT_KEY_KIND {
+                        if receipt_id_to_tx_hash.remove(id)?.is_some() {
+                            self.evicted_receipts += 1;
+                        }
+                    } else if data_id_to_tx_hash.remove(id)?.is_some() {
+                        self.evicted_data += 1;
+                    }
+                    block_height_index.remove(key)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses the environment variable `name`, or returns `default` when it is not set.
+pub(crate) fn parse_env_var<T: FromStr>(name: &'static str, default: T) -> Result<T> {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map_err(|_| Error::InvalidEnvVar { name, value }),
+        Err(_) => Ok(default),
+    }
+}
+
+/// In-memory mappings split into one generation per block, newest first, so that expiring a block
+/// drops its generation wholesale instead of visiting every entry.
+///
+/// Every generation shares one hasher, so an id is hashed once per lookup however many
+/// generations it probes.
+struct Generations {
+    generations: VecDeque<HashMap<CryptoHash, CryptoHash>>,
+    hasher: RandomState,
+    len: usize,
+}
+
+impl Generations {
+    fn new() -> Self {
+        let hasher = RandomState::new();
+        Self {
+            generations: VecDeque::from([HashMap::with_hasher(hasher.clone())]),
+            hasher,
+            len: 0,
+        }
+    }
+
+    fn get(&self, id: &CryptoHash) -> Option<CryptoHash> {
+        let hash = self.hasher.hash_one(id);
+        self.generations
+            .iter()
+            .find_map(|generation| generation.raw_entry().from_key_hashed_nocheck(hash, id))
+            .map(|(_, tx_hash)| *tx_hash)
+    }
+
+    /// An id inserted again shadows its older copy until that one expires, looking it up in every
+    /// generation would cost more than the duplicate.
+    fn insert(&mut self, id: CryptoHash, tx_hash: CryptoHash) {
+        if self.generations[0].insert(id, tx_hash).is_none() {
+            self.len += 1;
+        }
+    }
+
+    fn remove(&mut self, id: &CryptoHash) -> Option<CryptoHash> {
+        let mut tx_hash = None;
+        for generation in &mut self.generations {
+            if let Some(removed) = generation.remove(id) {
+                tx_hash.get_or_insert(removed);
+                self.len -= 1;
+            }
+        }
+        tx_hash
+    }
+
+    /// Starts a new generation and drops the ones that are too old, then the oldest ones until the
+    /// mappings fit into `max_entries`. Returns the number of dropped entries.
+    fn rotate(&mut self, max_entries: usize) -> u64 {
+        let len = self.len;
+        self.generations
+            .push_front(HashMap::with_hasher(self.hasher.clone()));
+        while self.generations.len() > MAX_AGE
+            || (self.len > max_entries && self.generations.len() > 1)
+        {
+            let generation = self.generations.pop_back().unwrap();
+            self.len -= generation.len();
+        }
+        (len - self.len) as u64
+    }
+}
+
+fn index_key(block_height: u64, kind: u8, id: &CryptoHash) -> Vec<u8> {
+    let mut key = block_height.to_be_bytes().to_vec();
+    key.push(kind);
+    key.extend_from_slice(id.as_ref());
+    key
+}
+
+fn decode_tx_hash(value: &[u8]) -> CryptoHash {
+    CryptoHash(
+        value
+            .try_into()
+            .expect("transaction hash expected to be 32 bytes"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{hash, open_sled};
+    use std::{
+        fs,
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn generations_expire_after_max_age() {
+        let id = hash("receipt");
+        let tx_hash = hash("transaction");
+        let mut generations = Generations::new();
+        generations.insert(id, tx_hash);
+
+        for _ in 1..MAX_AGE {
+            assert_eq!(generations.rotate(usize::MAX), 0);
+            assert_eq!(generations.get(&id), Some(tx_hash));
+        }
+        assert_eq!(generations.rotate(usize::MAX), 1);
+        assert_eq!(generations.get(&id), None);
+        assert_eq!(generations.len, 0);
+    }
+
+    #[test]
+    fn generations_reinsert_resets_age() {
+        let id = hash("receipt");
+        let tx_hash = hash("transaction");
+        let mut generations = Generations::new();
+        generations.insert(id, tx_hash);
+
+        for _ in 1..MAX_AGE {
+            generations.rotate(usize::MAX);
+        }
+        generations.insert(id, tx_hash);
+
+        for _ in 1..MAX_AGE {
+            generations.rotate(usize::MAX);
+            assert_eq!(generations.get(&id), Some(tx_hash));
+        }
+        assert_eq!(generations.rotate(usize::MAX), 1);
+        assert_eq!(generations.get(&id), None);
+        assert_eq!(generations.len, 0);
+    }
+
+    #[test]
+    fn generations_remove_reinserted() {
+        let id = hash("receipt");
+        let tx_hash = hash("transaction");
+        let mut generations = Generations::new();
+        generations.insert(id, tx_hash);
+        generations.rotate(usize::MAX);
+        generations.insert(id, tx_hash);
+
+        assert_eq!(generations.remove(&id), Some(tx_hash));
+        assert_eq!(generations.get(&id), None);
+        assert_eq!(generations.len, 0);
+    }
+
+    #[test]
+    fn generations_drop_oldest_over_max_entries() {
+        let mut generations = Generations::new();
+        for block in 0..4 {
+            generations.insert(hash(&block.to_string()), hash("transaction"));
+            generations.rotate(2);
+        }
+        assert_eq!(generations.len, 2);
+        assert_eq!(generations.get(&hash("1")), None);
+        assert!(generations.get(&hash("2")).is_some());
+        assert!(generations.get(&hash("3")).is_some());
+    }
+
+    #[test]
+    fn disk_store_survives_reopening_and_evicts_by_retention() {
+        let path = env::temp_dir().join(format!("lineage-store-{}", std::process::id()));
+        let [receipt_id, data_id, tx_hash] = ["receipt", "data", "transaction"].map(hash);
+        {
+            let db = open_sled(&path);
+            let mut lineage_store = LineageStore::open_disk(&db, 10).unwrap();
+            lineage_store
+                .insert_receipt(receipt_id, tx_hash, 1)
+                .unwrap();
+            lineage_store.insert_data(data_id, tx_hash, 5).unwrap();
+        }
+
+        let db = open_sled(&path);
+        let mut lineage_store = LineageStore::open_disk(&db, 10).unwrap();
+        assert_eq!(
+            lineage_store.get_receipt(&receipt_id).unwrap(),
+            Some(tx_hash)
+        );
+
+        lineage_store.evict(11).unwrap();
+        assert_eq!(
+            lineage_store.get_receipt(&receipt_id).unwrap(),
+            Some(tx_hash)
+        );
+        lineage_store.evict(12).unwrap();
+        assert_eq!(lineage_store.get_receipt(&receipt_id).unwrap(), None);
+        assert_eq!(lineage_store.evictions(), (1, 0));
+        lineage_store.evict(16).unwrap();
+        assert_eq!(lineage_store.remove_data(&data_id).unwrap(), None);
+        assert_eq!(lineage_store.evictions(), (1, 1));
+
+        drop((lineage_store, db));
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    /// Compares against the per-entry age counter that `Generations` replaced, run with
+    /// `cargo test --release -- --ignored --nocapture`. Every block looks up the ids of the previous
+    /// block `LOOKUPS_PER_ENTRY` times, as resolving lineage, building receipt rows and tracing do,
+    /// and the ids of an expired block once, which probes every generation.
+    #[test]
+    #[ignore]
+    fn generations_rotate_timing() {
+        const ENTRIES_PER_BLOCK: usize = 20_000;
+        const BLOCKS: usize = 100;
+        const LOOKUPS_PER_ENTRY: usize = 3;
+
+        let ids: Vec<Vec<CryptoHash>> = (0..BLOCKS)
+            .map(|block| {
+                (0..ENTRIES_PER_BLOCK)
+                    .map(|entry| hash(&format!("{} {}", block, entry)))
+                    .collect()
+            })
+            .collect();
+        let lookups = |block: usize| {
+            let recent_ids = ids[block.saturating_sub(1)].iter().cycle();
+            let expired_ids = ids[block.saturating_sub(MAX_AGE + 1)].iter();
+            recent_ids
+                .take(ENTRIES_PER_BLOCK * LOOKUPS_PER_ENTRY)
+                .chain(expired_ids)
+        };
+
+        let mut aged: HashMap<CryptoHash, (CryptoHash, u8)> = HashMap::new();
+        let (mut retain_elapsed, mut aged_get_elapsed, mut aged_found) =
+            (Duration::ZERO, Duration::ZERO, 0);
+        let started = Instant::now();
+        for (block, block_ids) in ids.iter().enumerate() {
+            for id in block_ids {
+                aged.insert(*id, (CryptoHash::default(), 0));
+            }
+            let start = Instant::now();
+            aged_found += lookups(block).filter(|id| aged.get(id).is_some()).count();
+            aged_get_elapsed += start.elapsed();
+            let start = Instant::now();
+            aged.retain(|_, (_, age)| {
+                *age += 1;
+                (*age as usize) < MAX_AGE
+            });
+            retain_elapsed += start.elapsed();
+        }
+        let retain_total = started.elapsed();
+
+        let mut generations = Generations::new();
+        let (mut rotate_elapsed, mut generations_get_elapsed, mut generations_found) =
+            (Duration::ZERO, Duration::ZERO, 0);
+        let started = Instant::now();
+        for (block, block_ids) in ids.iter().enumerate() {
+            for id in block_ids {
+                generations.insert(*id, CryptoHash::default());
+            }
+            let start = Instant::now();
+            generations_found += lookups(block)
+                .filter(|id| generations.get(id).is_some())
+                .count();
+            generations_get_elapsed += start.elapsed();
+            let start = Instant::now();
+            generations.rotate(usize::MAX);
+            rotate_elapsed += start.elapsed();
+        }
+        let rotate_total = started.elapsed();
+
+        assert_eq!(aged.len(), generations.len);
+        assert_eq!(aged_found, generations_found);
+        println!(
+            "{} blocks of {} entries: retain {:?}, lookups {:?}, {:?} in total with inserts; \
+             rotate {:?}, lookups {:?}, {:?} in total with inserts",
+            BLOCKS,
+            ENTRIES_PER_BLOCK,
+            retain_elapsed,
+            aged_get_elapsed,
+            retain_total,
+            rotate_elapsed,
+            generations_get_elapsed,
+            rotate_total
+        );
+    }
+}
@@ -1,20 +1,28 @@
+use crate::{balance::BalanceStore, lineage::LineageStore, trace::TraceTracker};
 use chrono::{DateTime, Utc};
 use near_jsonrpc_client::{methods, JsonRpcClient};
 use parking_lot::RwLock;
 use qlytics_core::Result;
 use std::{
     collections::VecDeque,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
 
-#[allow(clippy::await_holding_lock)]
+#[allow(clippy::await_holding_lock, clippy::too_many_arguments)]
 pub(crate) async fn log(
     block_height: u64,
     client: &Arc<JsonRpcClient>,
     time: &Arc<RwLock<Instant>>,
     eta: &Arc<RwLock<VecDeque<(Duration, u64)>>>,
     misses: &Arc<RwLock<u32>>,
+    malformed_events: &Arc<AtomicU32>,
+    lineage_store: &Arc<RwLock<LineageStore>>,
+    balance_store: &Arc<RwLock<BalanceStore>>,
+    trace_tracker: &Arc<RwLock<TraceTracker>>,
 ) -> Result<()> {
     let mut time = time.write();
     let elapsed = time.elapsed();
@@ -38,13 +46,22 @@ pub(crate) async fn log(
 
             let eta = (current_block_height - block_height) as f64 / blocks_per_millis;
             let eta = Duration::from_millis(eta as u64);
+            let (evicted_receipts, evicted_data) = lineage_store.read().evictions();
+            let evicted_balances = balance_store.read().evictions();
+            let (untraced_receipts, dropped_traces) = trace_tracker.read().dropped();
 
             println!(
-                "[{}] Height: {}, BPS: {:.1}, Misses: {}, ETA: {}",
+                "[{}] Height: {}, BPS: {:.1}, Misses: {}, Malformed events: {}, Evicted receipts: {}, Evicted data: {}, Evicted balances: {}, Untraced receipts: {}, Dropped traces: {}, ETA: {}",
                 utc.format("%Y-%m-%d %H:%M:%S"),
                 block_height,
                 blocks_per_millis as f32 * 1_000.,
                 misses.read(),
+                malformed_events.load(Ordering::Relaxed),
+                evicted_receipts,
+                evicted_data,
+                evicted_balances,
+                untraced_receipts,
+                dropped_traces,
                 humantime::Duration::from(eta)
             );
         }
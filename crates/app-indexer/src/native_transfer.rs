@@ -0,0 +1,62 @@
+use crate::trace::TraceTracker;
+use near_lake_framework::near_indexer_primitives::{
+    views::{ActionView, ReceiptEnumView},
+    CryptoHash, StreamerMessage,
+};
+use qlytics_graphql::{NativeTransfer, NativeTransferCause};
+
+/// Transfers from `system` are classified by the trace tracker, so this runs before it forgets
+/// the receipts executed in the block.
+pub(crate) fn handle_native_transfers(
+    msg: &StreamerMessage,
+    block_hash: CryptoHash,
+    timestamp: i64,
+    trace_tracker: &TraceTracker,
+) -> Vec<NativeTransfer> {
+    let mut native_transfers = vec![];
+    for outcome in msg
+        .shards
+        .iter()
+        .flat_map(|shard| &shard.receipt_execution_outcomes)
+    {
+        let ReceiptEnumView::Action { actions, .. } = &outcome.receipt.receipt else {
+            continue;
+        };
+        let receipt = &outcome.receipt;
+        let system_transfer = trace_tracker.system_transfer(receipt);
+        for (index, action) in actions.iter().enumerate() {
+            let (from_account_id, amount, cause) = match (action, system_transfer) {
+                (ActionView::Transfer { deposit }, Some((cause, from_account_id))) => {
+                    (from_account_id, *deposit, cause)
+                }
+                (ActionView::Transfer { deposit }, None) if receipt.predecessor_id.is_system() => (
+                    &receipt.predecessor_id,
+                    *deposit,
+                    NativeTransferCause::Unknown,
+                ),
+                (ActionView::Transfer { deposit }, None) => (
+                    &receipt.predecessor_id,
+                    *deposit,
+                    NativeTransferCause::Transfer,
+                ),
+                (ActionView::FunctionCall { deposit, .. }, _) if *deposit > 0 => (
+                    &receipt.predecessor_id,
+                    *deposit,
+                    NativeTransferCause::FunctionCallDeposit,
+                ),
+                _ => continue,
+            };
+            native_transfers.push(NativeTransfer::new(
+                outcome,
+                index as i64,
+                from_account_id,
+                &receipt.receiver_id,
+                amount,
+                cause,
+                block_hash,
+                timestamp,
+            ));
+        }
+    }
+    native_transfers
+}
@@ -0,0 +1,249 @@
+use crate::event::parse_event_log;
+use near_lake_framework::near_indexer_primitives::{CryptoHash, IndexerShard};
+use qlytics_graphql::{NftOwner, NftTransfer, TokenTransferCause};
+use rayon::prelude::*;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+const NEP171_STANDARD: &str = "nep171";
+
+#[derive(Deserialize)]
+struct NftMintData {
+    owner_id: String,
+    token_ids: Vec<String>,
+    memo: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct NftTransferData {
+    authorized_id: Option<String>,
+    old_owner_id: String,
+    new_owner_id: String,
+    token_ids: Vec<String>,
+    memo: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct NftBurnData {
+    authorized_id: Option<String>,
+    owner_id: String,
+    token_ids: Vec<String>,
+    memo: Option<String>,
+}
+
+type NftTransferRow = (
+    TokenTransferCause,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    Vec<String>,
+    Option<String>,
+);
+
+pub(crate) fn handle_nft_transfers(
+    shard: &IndexerShard,
+    block_hash: CryptoHash,
+    timestamp: i64,
+) -> Vec<NftTransfer> {
+    shard
+        .receipt_execution_outcomes
+        .par_iter()
+        .flat_map_iter(|outcome| {
+            outcome
+                .execution_outcome
+                .outcome
+                .logs
+                .iter()
+                .filter_map(|log| parse_event_log(log)?.ok())
+                .filter(|event_log| event_log.standard == NEP171_STANDARD)
+                .flat_map(|event_log| {
+                    let data = event_log.data.unwrap_or_default();
+                    let rows: Vec<NftTransferRow> = match event_log.event.as_str() {
+                        "nft_mint" => serde_json::from_value::<Vec<NftMintData>>(data)
+                            .unwrap_or_default()
+                            .into_iter()
+                            .map(|mint| {
+                                (
+                                    TokenTransferCause::Mint,
+                                    None,
+                                    Some(mint.owner_id),
+                                    None,
+                                    mint.token_ids,
+                                    mint.memo,
+                                )
+                            })
+                            .collect(),
+                        "nft_transfer" => serde_json::from_value::<Vec<NftTransferData>>(data)
+                            .unwrap_or_default()
+                            .into_iter()
+                            .map(|transfer| {
+                                (
+                                    TokenTransferCause::Transfer,
+                                    Some(transfer.old_owner_id),
+                                    Some(transfer.new_owner_id),
+                                    transfer.authorized_id,
+                                    transfer.token_ids,
+                                    transfer.memo,
+                                )
+                            })
+                            .collect(),
+                        "nft_burn" => serde_json::from_value::<Vec<NftBurnData>>(data)
+                            .unwrap_or_default()
+                            .into_iter()
+                            .map(|burn| {
+                                (
+                                    TokenTransferCause::Burn,
+                                    Some(burn.owner_id),
+                                    None,
+                                    burn.authorized_id,
+                                    burn.token_ids,
+                                    burn.memo,
+                                )
+                            })
+                            .collect(),
+                        _ => vec![],
+                    };
+                    rows
+                })
+                .flat_map(|(cause, from, to, authorized_id, token_ids, memo)| {
+                    token_ids.into_iter().map(move |token_id| {
+                        (
+                            cause,
+                            from.clone(),
+                            to.clone(),
+                            authorized_id.clone(),
+                            token_id,
+                            memo.clone(),
+                        )
+                    })
+                })
+                .enumerate()
+                .map(
+                    |(index_in_receipt, (cause, from, to, authorized_id, token_id, memo))| {
+                        NftTransfer::new(
+                            outcome,
+                            index_in_receipt as i64,
+                            &token_id,
+                            cause,
+                            from.as_deref(),
+                            to.as_deref(),
+                            authorized_id.as_deref(),
+                            memo.as_deref(),
+                            block_hash,
+                            timestamp,
+                        )
+                    },
+                )
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// The owner of every token touched in the block, after its last successful transfer.
+pub(crate) fn handle_nft_owners(nft_transfers: &[NftTransfer], block_height: u64) -> Vec<NftOwner> {
+    let mut owners: HashMap<(&str, &str), &NftTransfer> = HashMap::new();
+    for transfer in nft_transfers.iter().filter(|transfer| transfer.success) {
+        owners.insert(
+            (&transfer.contract_account_id, &transfer.token_id),
+            transfer,
+        );
+    }
+    owners
+        .into_values()
+        .map(|transfer| NftOwner::new(transfer, block_height))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{hash, receipt, receipt_outcome, shard};
+    use serde_json::json;
+
+    fn nft_transfers(logs: &[serde_json::Value]) -> Vec<NftTransfer> {
+        let mut outcome = receipt_outcome(receipt(hash("receipt"), "alice.near", "nft.near"), &[]);
+        outcome.execution_outcome.outcome.logs = logs
+            .iter()
+            .map(|log| format!("EVENT_JSON:{}", log))
+            .collect();
+        handle_nft_transfers(&shard(1, 0, vec![], vec![outcome]), hash("block"), 0)
+    }
+
+    fn nft_event(event: &str, data: serde_json::Value) -> serde_json::Value {
+        json!({ "standard": "nep171", "version": "1.0.0", "event": event, "data": data })
+    }
+
+    #[test]
+    fn nft_events_are_parsed_per_token() {
+        let transfers = nft_transfers(&[
+            nft_event(
+                "nft_mint",
+                json!([{ "owner_id": "alice.near", "token_ids": ["1", "2"] }]),
+            ),
+            nft_event(
+                "nft_transfer",
+                json!([{
+                    "authorized_id": "market.near",
+                    "old_owner_id": "alice.near",
+                    "new_owner_id": "bob.near",
+                    "token_ids": ["1"],
+                    "memo": "sale",
+                }]),
+            ),
+            nft_event(
+                "nft_burn",
+                json!([{ "owner_id": "alice.near", "token_ids": ["2"] }]),
+            ),
+        ]);
+
+        let rows: Vec<_> = transfers
+            .iter()
+            .map(|transfer| {
+                (
+                    transfer.index_in_receipt,
+                    transfer.token_id.as_str(),
+                    transfer.cause.as_str(),
+                    transfer.from_account_id.as_deref(),
+                    transfer.to_account_id.as_deref(),
+                    transfer.authorized_account_id.as_deref(),
+                    transfer.memo.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                (0, "1", "MINT", None, Some("alice.near"), None, None),
+                (1, "2", "MINT", None, Some("alice.near"), None, None),
+                (
+                    2,
+                    "1",
+                    "TRANSFER",
+                    Some("alice.near"),
+                    Some("bob.near"),
+                    Some("market.near"),
+                    Some("sale")
+                ),
+                (3, "2", "BURN", Some("alice.near"), None, None, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_and_foreign_nft_events_are_skipped() {
+        let transfers = nft_transfers(&[
+            nft_event("nft_mint", json!([{ "owner_id": "alice.near" }])),
+            nft_event("nft_transfer", json!({ "token_ids": ["1"] })),
+            nft_event("nft_mint", serde_json::Value::Null),
+            nft_event("nft_approve", json!([{ "token_ids": ["1"] }])),
+            json!({
+                "standard": "nep141",
+                "version": "1.0.0",
+                "event": "nft_mint",
+                "data": [{ "owner_id": "alice.near", "token_ids": ["1"] }],
+            }),
+            json!({ "standard": "nep171", "event": "nft_mint" }),
+        ]);
+        assert!(transfers.is_empty());
+    }
+}
@@ -1,14 +1,24 @@
+use crate::{lineage::LineageStore, trace::TraceTracker};
 use itertools::Itertools;
 use near_lake_framework::near_indexer_primitives::{
-    views::ReceiptEnumView, CryptoHash, IndexerChunkView, IndexerShard, StreamerMessage,
+    types::ShardId,
+    views::{ReceiptEnumView, ReceiptView},
+    CryptoHash, IndexerChunkView, IndexerShard, StreamerMessage,
 };
 use parking_lot::RwLock;
+use qlytics_core::{Error, Result};
 use qlytics_graphql::{
     ActionReceipt, ActionReceiptAction, ActionReceiptInputData, ActionReceiptOutputData,
-    DataReceipt, ExecutionOutcome, ExecutionOutcomeReceipt, Receipt,
+    DataReceipt, NativeTransferCause, Receipt, ReceiptLineage,
 };
 use rayon::prelude::*;
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+};
+
+/// Local receipts executed in the block, by shard.
+pub(crate) type LocalReceipts<'a> = HashMap<ShardId, Vec<&'a ReceiptView>>;
 
 #[allow(clippy::too_many_arguments, clippy::type_complexity)]
 pub(crate) fn handle_chunk_receipts(
@@ -17,9 +27,9 @@ pub(crate) fn handle_chunk_receipts(
     block_hash: CryptoHash,
     chunk_hash: CryptoHash,
     timestamp: i64,
-    receipt_id_to_tx_hash: &Arc<RwLock<HashMap<CryptoHash, (CryptoHash, u8)>>>,
-    data_id_to_tx_hash: &Arc<RwLock<HashMap<CryptoHash, CryptoHash>>>,
-    misses: &Arc<RwLock<u32>>,
+    local_receipts: &LocalReceipts,
+    receipt_id_to_tx_hash: &HashMap<CryptoHash, CryptoHash>,
+    refunds: &HashMap<CryptoHash, Option<CryptoHash>>,
 ) -> (
     Vec<Receipt>,
     Vec<DataReceipt>,
@@ -27,9 +37,8 @@ pub(crate) fn handle_chunk_receipts(
     Vec<ActionReceiptAction>,
     Vec<ActionReceiptInputData>,
     Vec<ActionReceiptOutputData>,
-    Vec<ExecutionOutcome>,
-    Vec<ExecutionOutcomeReceipt>,
 ) {
+    let receipt_views: Vec<_> = get_chunk_receipts(shard, chunk, local_receipts).collect();
     let (
         receipts,
         data_receipts,
@@ -37,55 +46,11 @@ pub(crate) fn handle_chunk_receipts(
         action_receipt_actions,
         action_receipt_input_datas,
         action_receipt_output_datas,
-        execution_outcome,
-        execution_outcome_receipts,
-    ): (
-        Vec<_>,
-        Vec<_>,
-        Vec<_>,
-        Vec<_>,
-        Vec<_>,
-        Vec<_>,
-        Vec<_>,
-        Vec<_>,
-    ) = chunk
-        .receipts
-        .par_iter()
+    ): (Vec<_>, Vec<_>, Vec<_>, Vec<_>, Vec<_>, Vec<_>) = receipt_views
+        .into_par_iter()
         .enumerate()
         .map(|(chunk_index, receipt_view)| {
-            let (execution_outcome, execution_outcome_receipts) = if let Some(outcome) = shard
-                .receipt_execution_outcomes
-                .iter()
-                .find(|r| r.execution_outcome.id == receipt_view.receipt_id)
-            {
-                let execution_outcome = Some(ExecutionOutcome::new(
-                    receipt_view,
-                    block_hash,
-                    chunk_index as i64,
-                    timestamp,
-                    &outcome.execution_outcome.outcome,
-                    shard.shard_id,
-                ));
-
-                let execution_outcome_receipts = outcome
-                    .execution_outcome
-                    .outcome
-                    .receipt_ids
-                    .iter()
-                    .enumerate()
-                    .map(|(index, receipt_id)| {
-                        ExecutionOutcomeReceipt::new(
-                            outcome.execution_outcome.id,
-                            index as i64,
-                            *receipt_id,
-                        )
-                    })
-                    .collect();
-                (execution_outcome, execution_outcome_receipts)
-            } else {
-                (None, vec![])
-            };
-
+            let refund = refunds.get(&receipt_view.receipt_id);
             let data_receipt =
                 if let ReceiptEnumView::Data { data_id, data } = &receipt_view.receipt {
                     Some(DataReceipt::new(
@@ -120,7 +85,13 @@ pub(crate) fn handle_chunk_receipts(
                     .iter()
                     .enumerate()
                     .map(|(index, action_view)| {
-                        ActionReceiptAction::new(receipt_view, index as i64, action_view, timestamp)
+                        ActionReceiptAction::new(
+                            receipt_view,
+                            index as i64,
+                            action_view,
+                            timestamp,
+                            refund.is_some(),
+                        )
                     })
                     .collect();
                 let action_receipt_input_datas: Vec<_> = input_data_ids
@@ -147,35 +118,16 @@ pub(crate) fn handle_chunk_receipts(
                 (None, None, None, None)
             };
 
-            let tx_hash = receipt_id_to_tx_hash
-                .write()
-                .get(&receipt_view.receipt_id)
-                .cloned();
-            let receipt = if let Some((tx_hash, _)) = tx_hash {
-                let receipt = Receipt::new(
-                    receipt_view,
-                    block_hash,
-                    chunk_hash,
-                    chunk_index as i64,
-                    timestamp,
-                    tx_hash,
-                );
-
-                if let ReceiptEnumView::Action {
-                    output_data_receivers,
-                    ..
-                } = &receipt_view.receipt
-                {
-                    output_data_receivers.iter().for_each(|receiver| {
-                        data_id_to_tx_hash.write().insert(receiver.data_id, tx_hash);
-                    });
-                }
-                Some(receipt)
-            } else {
-                let mut misses = misses.write();
-                *misses += 1;
-                None
-            };
+            let receipt = Receipt::new(
+                receipt_view,
+                block_hash,
+                chunk_hash,
+                chunk_index as i64,
+                timestamp,
+                receipt_id_to_tx_hash.get(&receipt_view.receipt_id).copied(),
+                refund.is_some(),
+                refund.copied().flatten(),
+            );
             (
                 receipt,
                 data_receipt,
@@ -183,15 +135,13 @@ pub(crate) fn handle_chunk_receipts(
                 action_receipt_actions,
                 action_receipt_input_datas,
                 action_receipt_output_datas,
-                execution_outcome,
-                execution_outcome_receipts,
             )
         })
         .collect::<Vec<_>>()
         .into_iter()
         .multiunzip();
     (
-        receipts.into_iter().flatten().collect(),
+        receipts,
         data_receipts.into_iter().flatten().collect(),
         action_receipts.into_iter().flatten().collect(),
         action_receipt_actions
@@ -209,43 +159,384 @@ pub(crate) fn handle_chunk_receipts(
             .flatten()
             .flatten()
             .collect(),
-        execution_outcome.into_iter().flatten().collect(),
-        execution_outcome_receipts.into_iter().flatten().collect(),
     )
 }
 
-pub(crate) fn handle_shard_receipts(
-    msg: &StreamerMessage,
-    receipt_id_to_tx_hash: &Arc<RwLock<HashMap<CryptoHash, (CryptoHash, u8)>>>,
-) {
-    let mut outcome_receipts: Vec<_> = msg
+/// Receipts of transactions whose signer is the receiver are local: they are executed by the
+/// shard of the transaction, right away or in a later block once delayed, and never show up in
+/// `chunk.receipts`. Their ids are kept in `pending_local_receipt_ids` until their execution
+/// outcome comes in.
+pub(crate) fn resolve_local_receipts<'a>(
+    msg: &'a StreamerMessage,
+    pending_local_receipt_ids: &mut HashSet<CryptoHash>,
+) -> LocalReceipts<'a> {
+    for transaction in msg
         .shards
         .iter()
-        .flat_map(|shard| {
-            shard.receipt_execution_outcomes.iter().flat_map(|outcome| {
-                outcome
-                    .execution_outcome
-                    .outcome
-                    .receipt_ids
-                    .iter()
-                    .map(|receipt_id| (outcome.execution_outcome.id, *receipt_id))
-            })
+        .filter_map(|shard| shard.chunk.as_ref())
+        .flat_map(|chunk| &chunk.transactions)
+        .filter(|transaction| {
+            transaction.transaction.signer_id == transaction.transaction.receiver_id
         })
-        .collect();
-    let mut retries_left = 5;
-    loop {
-        outcome_receipts.drain_filter(|(executed_receipt_id, produced_receipt_id)| {
-            let mut receipt_id_to_tx_hash = receipt_id_to_tx_hash.write();
-            if let Some(tx_hash) = receipt_id_to_tx_hash.get(executed_receipt_id).cloned() {
-                receipt_id_to_tx_hash.insert(*produced_receipt_id, tx_hash);
-                true
+    {
+        pending_local_receipt_ids.extend(
+            transaction
+                .outcome
+                .execution_outcome
+                .outcome
+                .receipt_ids
+                .first(),
+        );
+    }
+    msg.shards
+        .iter()
+        .map(|shard| {
+            let receipts = shard
+                .receipt_execution_outcomes
+                .iter()
+                .filter(|outcome| pending_local_receipt_ids.remove(&outcome.execution_outcome.id))
+                .map(|outcome| &outcome.receipt)
+                .collect();
+            (shard.shard_id, receipts)
+        })
+        .collect()
+}
+
+/// The receipts included in the chunk, followed by the local receipts executed with it.
+fn get_chunk_receipts<'a>(
+    shard: &IndexerShard,
+    chunk: &'a IndexerChunkView,
+    local_receipts: &'a LocalReceipts,
+) -> impl Iterator<Item = &'a ReceiptView> {
+    chunk.receipts.iter().chain(
+        local_receipts
+            .get(&shard.shard_id)
+            .into_iter()
+            .flatten()
+            .copied(),
+    )
+}
+
+/// Resolves the originating transaction of every receipt included in the block up front, so that
+/// the receipt rows can be built in parallel without touching the lineage store.
+pub(crate) fn resolve_block_lineage(
+    msg: &StreamerMessage,
+    local_receipts: &LocalReceipts,
+    lineage_store: &mut LineageStore,
+    unresolved_receipt_ids: &Arc<RwLock<HashMap<CryptoHash, (u64, u8)>>>,
+    misses: &Arc<RwLock<u32>>,
+) -> Result<HashMap<CryptoHash, CryptoHash>> {
+    let block_height = msg.block.header.height;
+    for shard in &msg.shards {
+        for outcome in &shard.receipt_execution_outcomes {
+            if let Some(tx_hash) = lineage_store.get_receipt(&outcome.execution_outcome.id)? {
+                for receipt_id in &outcome.execution_outcome.outcome.receipt_ids {
+                    lineage_store.insert_receipt(*receipt_id, tx_hash, block_height)?;
+                }
+            }
+        }
+        let Some(chunk) = &shard.chunk else {
+            continue;
+        };
+        for transaction in &chunk.transactions {
+            if let Some(receipt) = &transaction.outcome.receipt {
+                lineage_store.insert_receipt(
+                    receipt.receipt_id,
+                    transaction.transaction.hash,
+                    block_height,
+                )?;
+            }
+            for receipt_id in &transaction.outcome.execution_outcome.outcome.receipt_ids {
+                lineage_store.insert_receipt(
+                    *receipt_id,
+                    transaction.transaction.hash,
+                    block_height,
+                )?;
+            }
+        }
+        for receipt in &chunk.receipts {
+            if let ReceiptEnumView::Data { data_id, .. } = receipt.receipt {
+                if let Some(tx_hash) = lineage_store.remove_data(&data_id)? {
+                    lineage_store.insert_receipt(receipt.receipt_id, tx_hash, block_height)?;
+                }
+            }
+        }
+    }
+
+    let mut receipt_id_to_tx_hash = HashMap::new();
+    for shard in &msg.shards {
+        let Some(chunk) = &shard.chunk else {
+            continue;
+        };
+        for receipt_view in get_chunk_receipts(shard, chunk, local_receipts) {
+            if let Some(tx_hash) = lineage_store.get_receipt(&receipt_view.receipt_id)? {
+                if let ReceiptEnumView::Action {
+                    output_data_receivers,
+                    ..
+                } = &receipt_view.receipt
+                {
+                    for receiver in output_data_receivers {
+                        lineage_store.insert_data(receiver.data_id, tx_hash, block_height)?;
+                    }
+                }
+                receipt_id_to_tx_hash.insert(receipt_view.receipt_id, tx_hash);
             } else {
-                false
+                unresolved_receipt_ids
+                    .write()
+                    .insert(receipt_view.receipt_id, (block_height, 0));
+                *misses.write() += 1;
             }
-        });
-        retries_left -= 1;
-        if retries_left == 0 || outcome_receipts.is_empty() {
-            break;
         }
     }
+    Ok(receipt_id_to_tx_hash)
+}
+
+/// Refund receipts included in the block, with the receipt whose execution produced them when
+/// it is known.
+pub(crate) fn resolve_refunds(
+    msg: &StreamerMessage,
+    local_receipts: &LocalReceipts,
+    trace_tracker: &TraceTracker,
+) -> HashMap<CryptoHash, Option<CryptoHash>> {
+    let mut refunds = HashMap::new();
+    for shard in &msg.shards {
+        let Some(chunk) = &shard.chunk else {
+            continue;
+        };
+        for receipt_view in get_chunk_receipts(shard, chunk, local_receipts) {
+            if let Some((NativeTransferCause::GasRefund | NativeTransferCause::DepositRefund, _)) =
+                trace_tracker.system_transfer(receipt_view)
+            {
+                refunds.insert(
+                    receipt_view.receipt_id,
+                    trace_tracker.parent_receipt_id(&receipt_view.receipt_id),
+                );
+            }
+        }
+    }
+    refunds
+}
+
+/// Propagates lineage along the executed receipt -> produced receipts graph of the block in
+/// dependency order, so chains of any depth and spanning shards get resolved in one traversal.
+pub(crate) fn handle_shard_receipts(
+    msg: &StreamerMessage,
+    lineage_store: &Arc<RwLock<LineageStore>>,
+) -> Result<()> {
+    let mut produced_receipt_ids: HashMap<CryptoHash, Vec<CryptoHash>> = HashMap::new();
+    for outcome in msg
+        .shards
+        .iter()
+        .flat_map(|shard| &shard.receipt_execution_outcomes)
+    {
+        produced_receipt_ids
+            .entry(outcome.execution_outcome.id)
+            .or_default()
+            .extend(&outcome.execution_outcome.outcome.receipt_ids);
+    }
+    let mut lineage_store = lineage_store.write();
+    let mut queue = VecDeque::new();
+    for executed_receipt_id in produced_receipt_ids.keys() {
+        if let Some(tx_hash) = lineage_store.get_receipt(executed_receipt_id)? {
+            queue.push_back((*executed_receipt_id, tx_hash));
+        }
+    }
+    while let Some((executed_receipt_id, tx_hash)) = queue.pop_front() {
+        let Some(receipt_ids) = produced_receipt_ids.remove(&executed_receipt_id) else {
+            continue;
+        };
+        for receipt_id in receipt_ids {
+            lineage_store.insert_receipt(receipt_id, tx_hash, msg.block.header.height)?;
+            queue.push_back((receipt_id, tx_hash));
+        }
+    }
+    Ok(())
+}
+
+/// Receipts emitted without a transaction whose lineage has been discovered since.
+pub(crate) fn handle_unresolved_receipts(
+    lineage_store: &Arc<RwLock<LineageStore>>,
+    unresolved_receipt_ids: &Arc<RwLock<HashMap<CryptoHash, (u64, u8)>>>,
+) -> Result<Vec<ReceiptLineage>> {
+    let lineage_store = lineage_store.read();
+    let mut unresolved_receipt_ids = unresolved_receipt_ids.write();
+    let mut receipt_lineages = vec![];
+    for receipt_id in unresolved_receipt_ids.keys() {
+        if let Some(tx_hash) = lineage_store.get_receipt(receipt_id)? {
+            receipt_lineages.push((*receipt_id, tx_hash));
+        }
+    }
+    Ok(receipt_lineages
+        .into_iter()
+        .map(|(receipt_id, tx_hash)| {
+            unresolved_receipt_ids.remove(&receipt_id);
+            ReceiptLineage::new(receipt_id, tx_hash)
+        })
+        .collect())
+}
+
+/// Fails on the first receipt of the block whose originating transaction is still unknown.
+pub(crate) fn check_lineage(
+    msg: &StreamerMessage,
+    local_receipts: &LocalReceipts,
+    unresolved_receipt_ids: &Arc<RwLock<HashMap<CryptoHash, (u64, u8)>>>,
+) -> Result<()> {
+    let unresolved_receipt_ids = unresolved_receipt_ids.read();
+    for shard in &msg.shards {
+        let Some(chunk) = &shard.chunk else {
+            continue;
+        };
+        let unresolved_receipt = get_chunk_receipts(shard, chunk, local_receipts)
+            .find(|receipt| unresolved_receipt_ids.contains_key(&receipt.receipt_id));
+        if let Some(receipt) = unresolved_receipt {
+            return Err(match receipt.receipt {
+                ReceiptEnumView::Data { data_id, .. } => Error::UnresolvedData {
+                    block_height: msg.block.header.height,
+                    shard_id: shard.shard_id,
+                    receipt_id: receipt.receipt_id.to_string(),
+                    data_id: data_id.to_string(),
+                },
+                ReceiptEnumView::Action { .. } => Error::UnresolvedReceipt {
+                    block_height: msg.block.header.height,
+                    shard_id: shard.shard_id,
+                    receipt_id: receipt.receipt_id.to_string(),
+                },
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{hash, receipt, receipt_outcome, shard, streamer_message, transaction};
+
+    #[test]
+    fn deep_receipt_chain_across_shards_resolves_to_transaction() {
+        let block_height = 100;
+        let tx_hash = hash("transaction");
+        let receipt_ids: Vec<_> = (0..8).map(|i| hash(&format!("receipt {}", i))).collect();
+
+        // Each hop moves to the other shard, and the outcomes are listed deepest first so that
+        // neither shard order nor outcome order matches the dependency order.
+        let mut outcomes = [vec![], vec![]];
+        for (depth, receipt_id) in receipt_ids.iter().enumerate().rev() {
+            let account_id = format!("shard{}.near", depth % 2);
+            outcomes[depth % 2].push(receipt_outcome(
+                receipt(*receipt_id, "alice.near", &account_id),
+                &receipt_ids
+                    .get(depth + 1)
+                    .into_iter()
+                    .copied()
+                    .collect::<Vec<_>>(),
+            ));
+        }
+        let [shard0_outcomes, shard1_outcomes] = outcomes;
+        let msg = streamer_message(
+            block_height,
+            vec![
+                shard(block_height, 0, vec![], shard0_outcomes),
+                shard(
+                    block_height,
+                    1,
+                    vec![transaction(tx_hash, "alice.near", receipt_ids[0])],
+                    shard1_outcomes,
+                ),
+            ],
+        );
+
+        let lineage_store = Arc::new(RwLock::new(LineageStore::open(None).unwrap()));
+        let unresolved_receipt_ids = Arc::new(RwLock::new(HashMap::new()));
+        let misses = Arc::new(RwLock::new(0));
+        resolve_block_lineage(
+            &msg,
+            &HashMap::new(),
+            &mut lineage_store.write(),
+            &unresolved_receipt_ids,
+            &misses,
+        )
+        .unwrap();
+        handle_shard_receipts(&msg, &lineage_store).unwrap();
+        let receipt_lineages =
+            handle_unresolved_receipts(&lineage_store, &unresolved_receipt_ids).unwrap();
+
+        for receipt_id in &receipt_ids {
+            assert_eq!(
+                lineage_store.read().get_receipt(receipt_id).unwrap(),
+                Some(tx_hash)
+            );
+        }
+        assert_eq!(receipt_lineages.len() as u32, *misses.read());
+        assert!(unresolved_receipt_ids.read().is_empty());
+    }
+
+    #[test]
+    fn delayed_local_receipt_is_indexed_when_executed() {
+        let tx_hash = hash("transaction");
+        let local_receipt_id = hash("local receipt");
+        let incoming_receipt_id = hash("incoming receipt");
+        let mut pending_local_receipt_ids = HashSet::new();
+
+        let msg = streamer_message(
+            1,
+            vec![shard(
+                1,
+                0,
+                vec![transaction(tx_hash, "alice.near", local_receipt_id)],
+                vec![],
+            )],
+        );
+        let local_receipts = resolve_local_receipts(&msg, &mut pending_local_receipt_ids);
+        assert!(local_receipts[&0].is_empty());
+        assert!(pending_local_receipt_ids.contains(&local_receipt_id));
+
+        // Local receipts never show up in `chunk.receipts`, unlike the incoming one.
+        let mut shard = shard(
+            2,
+            0,
+            vec![],
+            vec![
+                receipt_outcome(receipt(incoming_receipt_id, "bob.near", "alice.near"), &[]),
+                receipt_outcome(receipt(local_receipt_id, "alice.near", "alice.near"), &[]),
+            ],
+        );
+        if let Some(chunk) = &mut shard.chunk {
+            chunk
+                .receipts
+                .retain(|receipt| receipt.receipt_id != local_receipt_id);
+        }
+        let msg = streamer_message(2, vec![shard]);
+        let local_receipts = resolve_local_receipts(&msg, &mut pending_local_receipt_ids);
+        assert!(pending_local_receipt_ids.is_empty());
+
+        let shard = &msg.shards[0];
+        let (receipts, ..) = handle_chunk_receipts(
+            shard,
+            shard.chunk.as_ref().unwrap(),
+            msg.block.header.hash,
+            shard.chunk.as_ref().unwrap().header.chunk_hash,
+            0,
+            &local_receipts,
+            &HashMap::from([(local_receipt_id, tx_hash)]),
+            &HashMap::new(),
+        );
+        let mut receipts: Vec<_> = receipts
+            .into_iter()
+            .map(|receipt| {
+                (
+                    receipt.receipt_id,
+                    receipt.index_in_chunk,
+                    receipt.originated_from_transaction_hash,
+                )
+            })
+            .collect();
+        receipts.sort();
+        let mut expected = [
+            (incoming_receipt_id.to_string(), 0, None),
+            (local_receipt_id.to_string(), 1, Some(tx_hash.to_string())),
+        ];
+        expected.sort();
+        assert_eq!(receipts, expected);
+    }
 }
@@ -1,16 +1,175 @@
-use near_lake_framework::near_indexer_primitives::{views::StateChangeWithCauseView, CryptoHash};
+use crate::balance::BalanceStore;
+use near_lake_framework::near_indexer_primitives::{
+    views::{ActionView, ReceiptEnumView, StateChangeCauseView, StateChangeValueView},
+    CryptoHash, StreamerMessage,
+};
+use parking_lot::RwLock;
+use qlytics_core::Result;
 use qlytics_graphql::AccountChange;
+use std::{collections::HashSet, sync::Arc};
 
-pub fn handle_state_changes(
-    state_changes: &[StateChangeWithCauseView],
+/// Runs sequentially, as every change is compared against the account's previous balances.
+pub(crate) fn handle_state_changes(
+    msg: &StreamerMessage,
     block_hash: CryptoHash,
     timestamp: i64,
-) -> Vec<AccountChange> {
-    state_changes
+    balance_store: &Arc<RwLock<BalanceStore>>,
+) -> Result<Vec<AccountChange>> {
+    let stake_receipt_ids: HashSet<_> = msg
+        .shards
         .iter()
-        .enumerate()
-        .filter_map(|(index_in_block, state_change)| {
-            AccountChange::new(state_change, block_hash, timestamp, index_in_block as i64)
+        .flat_map(|shard| &shard.receipt_execution_outcomes)
+        .filter(|outcome| match &outcome.receipt.receipt {
+            ReceiptEnumView::Action { actions, .. } => actions
+                .iter()
+                .any(|action| matches!(action, ActionView::Stake { .. })),
+            ReceiptEnumView::Data { .. } => false,
         })
-        .collect()
+        .map(|outcome| outcome.receipt.receipt_id)
+        .collect();
+
+    let mut balance_store = balance_store.write();
+    let mut account_changes = vec![];
+    for (index_in_block, state_change) in msg
+        .shards
+        .iter()
+        .filter(|shard| shard.chunk.is_some())
+        .flat_map(|shard| shard.state_changes.iter().enumerate())
+    {
+        let (account_id, balances) = match &state_change.value {
+            StateChangeValueView::AccountUpdate {
+                account_id,
+                account,
+            } => (
+                account_id,
+                Some((account.amount, account.locked, account.storage_usage)),
+            ),
+            StateChangeValueView::AccountDeletion { account_id } => (account_id, None),
+            _ => continue,
+        };
+        let previous_balances = balance_store.replace_balances(account_id, balances)?;
+        let is_stake = match &state_change.cause {
+            StateChangeCauseView::ActionReceiptProcessingStarted { receipt_hash }
+            | StateChangeCauseView::ReceiptProcessing { receipt_hash }
+            | StateChangeCauseView::PostponedReceipt { receipt_hash } => {
+                stake_receipt_ids.contains(receipt_hash)
+            }
+            _ => false,
+        };
+        account_changes.extend(AccountChange::new(
+            state_change,
+            block_hash,
+            timestamp,
+            index_in_block as i64,
+            previous_balances,
+            is_stake,
+        ));
+    }
+    Ok(account_changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{hash, receipt, receipt_outcome, shard, streamer_message};
+    use near_lake_framework::near_indexer_primitives::views::StateChangeWithCauseView;
+    use serde_json::{json, Value};
+
+    fn account_update(cause: Value, account_id: &str, amount: u128, locked: u128) -> Value {
+        json!({
+            "cause": cause,
+            "type": "account_update",
+            "change": {
+                "account_id": account_id,
+                "amount": amount.to_string(),
+                "locked": locked.to_string(),
+                "code_hash": CryptoHash::default(),
+                "storage_usage": 100,
+            },
+        })
+    }
+
+    #[test]
+    fn balance_change_causes() {
+        let [tx_hash, stake, transfer] = ["transaction", "stake", "transfer"].map(hash);
+        let mut stake_receipt = receipt(stake, "alice.near", "alice.near");
+        if let ReceiptEnumView::Action { actions, .. } = &mut stake_receipt.receipt {
+            actions.push(ActionView::Stake {
+                stake: 50,
+                public_key: "ed25519:11111111111111111111111111111111".parse().unwrap(),
+            });
+        }
+        let mut shard = shard(
+            1,
+            0,
+            vec![],
+            vec![
+                receipt_outcome(stake_receipt, &[]),
+                receipt_outcome(receipt(transfer, "alice.near", "bob.near"), &[]),
+            ],
+        );
+        shard.state_changes = [
+            account_update(
+                json!({ "type": "transaction_processing", "tx_hash": tx_hash }),
+                "alice.near",
+                100,
+                0,
+            ),
+            account_update(
+                json!({ "type": "receipt_processing", "receipt_hash": stake }),
+                "alice.near",
+                50,
+                50,
+            ),
+            account_update(
+                json!({ "type": "receipt_processing", "receipt_hash": transfer }),
+                "bob.near",
+                10,
+                10,
+            ),
+            account_update(
+                json!({ "type": "receipt_processing", "receipt_hash": transfer }),
+                "bob.near",
+                20,
+                0,
+            ),
+        ]
+        .into_iter()
+        .map(|state_change| {
+            serde_json::from_value::<StateChangeWithCauseView>(state_change).unwrap()
+        })
+        .collect();
+        let msg = streamer_message(1, vec![shard]);
+        let balance_store = Arc::new(RwLock::new(BalanceStore::open(None).unwrap()));
+
+        let account_changes: Vec<_> =
+            handle_state_changes(&msg, msg.block.header.hash, 0, &balance_store)
+                .unwrap()
+                .into_iter()
+                .map(|account_change| {
+                    (
+                        account_change.balance_change_cause,
+                        account_change.nonstaked_balance_delta,
+                        account_change.staked_balance_delta,
+                    )
+                })
+                .collect();
+        assert_eq!(
+            account_changes,
+            [
+                ("GAS_PURCHASE".to_string(), None, None),
+                (
+                    "STAKING".to_string(),
+                    Some("-50".to_string()),
+                    Some("50".to_string())
+                ),
+                ("TRANSFER".to_string(), None, None),
+                (
+                    "TRANSFER".to_string(),
+                    Some("10".to_string()),
+                    Some("-10".to_string())
+                ),
+            ]
+        );
+    }
 }
@@ -0,0 +1,628 @@
+use near_lake_framework::near_indexer_primitives::{
+    types::AccountId,
+    views::{ActionView, ExecutionStatusView, ReceiptEnumView, ReceiptView},
+    CryptoHash, IndexerExecutionOutcomeWithReceipt, StreamerMessage,
+};
+use qlytics_graphql::{NativeTransferCause, ReceiptTrace, TransactionFee, TransactionSummary};
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+};
+
+/// Follows every produced receipt until it executes, along with the receipt tree of every
+/// transaction seen since the indexer started until all of its receipts have executed.
+///
+/// The trees are only kept in memory. Receipts of transactions submitted before a restart are
+/// counted as untraced, and receipts and trees still pending after `TRACE_MAX_PENDING_BLOCKS`
+/// blocks are dropped.
+pub(crate) struct TraceTracker {
+    pending_receipts: HashMap<CryptoHash, PendingReceipt>,
+    transactions: HashMap<CryptoHash, TransactionTrace>,
+    max_pending_blocks: u64,
+    untraced_receipts: u64,
+    dropped_transactions: u64,
+}
+
+/// A receipt produced but not executed yet.
+struct PendingReceipt {
+    /// `None` for the receipt a transaction was converted into.
+    parent_receipt_id: Option<CryptoHash>,
+    parent_failed: bool,
+    /// The deleted account and its beneficiary, when produced by a `DeleteAccount` action.
+    deleted_account: Option<(AccountId, AccountId)>,
+    /// The transaction it originates from and its depth in the tree, `None` when the tree is not
+    /// traced.
+    trace: Option<(CryptoHash, i64)>,
+    block_height: u64,
+}
+
+struct TransactionTrace {
+    signer_id: AccountId,
+    block_height: u64,
+    pending_receipts: usize,
+    total_receipts: i64,
+    total_gas_burnt: u64,
+    conversion_tokens_burnt: u128,
+    total_tokens_burnt: u128,
+    /// Gas refunds back to the signer, `tokens_burnt` never includes them. Deposit refunds and
+    /// `DeleteAccount` payouts are no fees and left out.
+    gas_refunded: u128,
+    success: bool,
+}
+
+impl TraceTracker {
+    pub(crate) fn new() -> Self {
+        Self {
+            pending_receipts: HashMap::new(),
+            transactions: HashMap::new(),
+            max_pending_blocks: env::var("TRACE_MAX_PENDING_BLOCKS")
+                .map(|s| s.parse::<u64>().unwrap())
+                .unwrap_or(1_000),
+            untraced_receipts: 0,
+            dropped_transactions: 0,
+        }
+    }
+
+    /// Number of executed receipts that belong to no traced transaction, and of transactions
+    /// dropped before their tree completed.
+    pub(crate) fn dropped(&self) -> (u64, u64) {
+        (self.untraced_receipts, self.dropped_transactions)
+    }
+
+    /// The receipt whose execution produced `receipt_id`, as long as it has not executed yet.
+    pub(crate) fn parent_receipt_id(&self, receipt_id: &CryptoHash) -> Option<CryptoHash> {
+        self.pending_receipts.get(receipt_id)?.parent_receipt_id
+    }
+
+    /// Classifies a receipt sent by `system`, returning the cause and the account the funds come
+    /// from. Gas refunds keep the signer of the refunded receipt, while deposit refunds and
+    /// `DeleteAccount` payouts are signed by `system`. Deposit refunds are only produced by failed
+    /// receipts, older gas refunds signed by `system` are reported as deposit refunds there. `None`
+    /// for receipts signed by `system` whose parent is not tracked, e.g. produced before a restart.
+    pub(crate) fn system_transfer<'a>(
+        &'a self,
+        receipt: &'a ReceiptView,
+    ) -> Option<(NativeTransferCause, &'a AccountId)> {
+        let ReceiptEnumView::Action { signer_id, .. } = &receipt.receipt else {
+            return None;
+        };
+        if !receipt.predecessor_id.is_system() {
+            return None;
+        }
+        if !signer_id.is_system() {
+            return Some((NativeTransferCause::GasRefund, &receipt.predecessor_id));
+        }
+        match self.pending_receipts.get(&receipt.receipt_id) {
+            Some(PendingReceipt {
+                deleted_account: Some((account_id, beneficiary_id)),
+                ..
+            }) if *beneficiary_id == receipt.receiver_id => {
+                Some((NativeTransferCause::DeleteAccount, account_id))
+            }
+            Some(PendingReceipt {
+                parent_failed: true,
+                ..
+            }) => Some((NativeTransferCause::DepositRefund, &receipt.predecessor_id)),
+            Some(_) => Some((NativeTransferCause::GasRefund, &receipt.predecessor_id)),
+            None => None,
+        }
+    }
+
+    /// Runs once the lineage of the block is resolved, after everything else looking up the
+    /// receipts executed in the block.
+    pub(crate) fn handle_block(
+        &mut self,
+        msg: &StreamerMessage,
+    ) -> (
+        Vec<ReceiptTrace>,
+        Vec<TransactionSummary>,
+        Vec<TransactionFee>,
+    ) {
+        let block_height = msg.block.header.height;
+        let mut receipt_traces = vec![];
+        let mut completed_transactions = vec![];
+
+        for transaction in msg
+            .shards
+            .iter()
+            .filter_map(|shard| shard.chunk.as_ref())
+            .flat_map(|chunk| &chunk.transactions)
+        {
+            let transaction_hash = transaction.transaction.hash;
+            let outcome = &transaction.outcome.execution_outcome.outcome;
+            let mut trace = TransactionTrace {
+                signer_id: transaction.transaction.signer_id.clone(),
+                block_height,
+                pending_receipts: 0,
+                total_receipts: 0,
+                total_gas_burnt: outcome.gas_burnt,
+                conversion_tokens_burnt: outcome.tokens_burnt,
+                total_tokens_burnt: outcome.tokens_burnt,
+                gas_refunded: 0,
+                success: !matches!(outcome.status, ExecutionStatusView::Failure(_)),
+            };
+            for receipt_id in &outcome.receipt_ids {
+                self.pending_receipts.insert(
+                    *receipt_id,
+                    PendingReceipt {
+                        parent_receipt_id: None,
+                        parent_failed: false,
+                        deleted_account: None,
+                        trace: Some((transaction_hash, 0)),
+                        block_height,
+                    },
+                );
+                trace.pending_receipts += 1;
+                receipt_traces.push(ReceiptTrace::new(*receipt_id, transaction_hash, None, 0));
+            }
+            if trace.pending_receipts == 0 {
+                completed_transactions.push(transaction_hash);
+            }
+            self.transactions.insert(transaction_hash, trace);
+        }
+
+        // Receipts produced and executed within the block may come in any shard order, so an
+        // outcome is processed once the receipt that produced it is known, or right away when it
+        // was not produced within the block.
+        let mut outcomes: Vec<_> = msg
+            .shards
+            .iter()
+            .flat_map(|shard| &shard.receipt_execution_outcomes)
+            .collect();
+        let produced_receipt_ids: HashSet<_> = outcomes
+            .iter()
+            .flat_map(|outcome| &outcome.execution_outcome.outcome.receipt_ids)
+            .copied()
+            .collect();
+        while !outcomes.is_empty() {
+            let outcomes_len = outcomes.len();
+            let mut deferred_outcomes = vec![];
+            for outcome in outcomes {
+                let receipt_id = outcome.execution_outcome.id;
+                if !self.pending_receipts.contains_key(&receipt_id)
+                    && produced_receipt_ids.contains(&receipt_id)
+                {
+                    deferred_outcomes.push(outcome);
+                    continue;
+                }
+                self.handle_outcome(
+                    outcome,
+                    block_height,
+                    &mut receipt_traces,
+                    &mut completed_transactions,
+                );
+            }
+            if deferred_outcomes.len() == outcomes_len {
+                break;
+            }
+            outcomes = deferred_outcomes;
+        }
+
+        let (transaction_summaries, transaction_fees) = completed_transactions
+            .into_iter()
+            .filter_map(|transaction_hash| {
+                let trace = self.transactions.remove(&transaction_hash)?;
+                Some((
+                    TransactionSummary::new(
+                        transaction_hash,
+                        trace.total_receipts,
+                        trace.total_gas_burnt,
+                        trace.total_tokens_burnt,
+                        trace.success,
+                        msg.block.header.hash,
+                        block_height,
+                    ),
+                    TransactionFee::new(
+                        transaction_hash,
+                        &trace.signer_id,
+                        trace.conversion_tokens_burnt,
+                        trace.total_tokens_burnt,
+                        trace.gas_refunded,
+                        block_height,
+                    ),
+                ))
+            })
+            .unzip();
+
+        let expired = |height: u64| height + self.max_pending_blocks < block_height;
+        self.pending_receipts
+            .retain(|_, pending_receipt| !expired(pending_receipt.block_height));
+        self.dropped_transactions += self
+            .transactions
+            .drain_filter(|_, trace| expired(trace.block_height))
+            .count() as u64;
+
+        (receipt_traces, transaction_summaries, transaction_fees)
+    }
+
+    fn handle_outcome(
+        &mut self,
+        outcome_with_receipt: &IndexerExecutionOutcomeWithReceipt,
+        block_height: u64,
+        receipt_traces: &mut Vec<ReceiptTrace>,
+        completed_transactions: &mut Vec<CryptoHash>,
+    ) {
+        let outcome = &outcome_with_receipt.execution_outcome;
+        let receipt = &outcome_with_receipt.receipt;
+        let failed = matches!(outcome.outcome.status, ExecutionStatusView::Failure(_));
+
+        let system_transfer = self.system_transfer(receipt).map(|(cause, _)| cause);
+        let mut trace = self
+            .pending_receipts
+            .remove(&outcome.id)
+            .and_then(|pending_receipt| pending_receipt.trace)
+            .and_then(|(transaction_hash, depth)| {
+                self.transactions
+                    .get_mut(&transaction_hash)
+                    .map(|trace| (transaction_hash, trace, depth))
+            });
+
+        match &mut trace {
+            Some((_, trace, _)) => {
+                trace.pending_receipts -= 1;
+                trace.total_receipts += 1;
+                trace.total_gas_burnt += outcome.outcome.gas_burnt;
+                trace.total_tokens_burnt += outcome.outcome.tokens_burnt;
+                if failed {
+                    trace.success = false;
+                }
+                if let (
+                    ReceiptEnumView::Action { actions, .. },
+                    Some(NativeTransferCause::GasRefund),
+                ) = (&receipt.receipt, system_transfer)
+                {
+                    for action in actions {
+                        if let ActionView::Transfer { deposit } = action {
+                            trace.gas_refunded += deposit;
+                        }
+                    }
+                }
+            }
+            None => self.untraced_receipts += 1,
+        }
+
+        let deleted_account = match &receipt.receipt {
+            ReceiptEnumView::Action { actions, .. } if !failed => {
+                actions.iter().find_map(|action| match action {
+                    ActionView::DeleteAccount { beneficiary_id } => {
+                        Some((receipt.receiver_id.clone(), beneficiary_id.clone()))
+                    }
+                    _ => None,
+                })
+            }
+            _ => None,
+        };
+        for receipt_id in &outcome.outcome.receipt_ids {
+            self.pending_receipts.insert(
+                *receipt_id,
+                PendingReceipt {
+                    parent_receipt_id: Some(outcome.id),
+                    parent_failed: failed,
+                    deleted_account: deleted_account.clone(),
+                    trace: trace
+                        .as_ref()
+                        .map(|(transaction_hash, _, depth)| (*transaction_hash, depth + 1)),
+                    block_height,
+                },
+            );
+            if let Some((transaction_hash, trace, depth)) = &mut trace {
+                trace.pending_receipts += 1;
+                receipt_traces.push(ReceiptTrace::new(
+                    *receipt_id,
+                    *transaction_hash,
+                    Some(outcome.id),
+                    *depth + 1,
+                ));
+            }
+        }
+        if let Some((transaction_hash, trace, _)) = trace {
+            if trace.pending_receipts == 0 {
+                completed_transactions.push(transaction_hash);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        fixtures::{hash, receipt, receipt_outcome, shard, streamer_message, transaction},
+        lineage::LineageStore,
+        receipt::{handle_shard_receipts, resolve_block_lineage},
+    };
+    use near_lake_framework::near_indexer_primitives::IndexerTransactionWithOutcome;
+    use parking_lot::RwLock;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    fn signed(
+        transaction_hash: CryptoHash,
+        receipt_id: CryptoHash,
+    ) -> IndexerTransactionWithOutcome {
+        let mut transaction = transaction(transaction_hash, "alice.near", receipt_id);
+        transaction.outcome.execution_outcome.outcome.gas_burnt = 5;
+        transaction.outcome.execution_outcome.outcome.tokens_burnt = 500;
+        transaction
+    }
+
+    fn executed(
+        receipt_id: CryptoHash,
+        receipt_ids: &[CryptoHash],
+        gas_burnt: u64,
+    ) -> IndexerExecutionOutcomeWithReceipt {
+        let mut outcome =
+            receipt_outcome(receipt(receipt_id, "alice.near", "bob.near"), receipt_ids);
+        outcome.execution_outcome.outcome.gas_burnt = gas_burnt;
+        outcome.execution_outcome.outcome.tokens_burnt = gas_burnt as u128 * 100;
+        outcome
+    }
+
+    fn failed(
+        mut outcome: IndexerExecutionOutcomeWithReceipt,
+    ) -> IndexerExecutionOutcomeWithReceipt {
+        outcome.execution_outcome.outcome.status = serde_json::from_value(json!({
+            "Failure": {
+                "ActionError": {
+                    "index": 0,
+                    "kind": { "AccountDoesNotExist": { "account_id": "bob.near" } },
+                },
+            },
+        }))
+        .unwrap();
+        outcome
+    }
+
+    /// A transfer from `system` to alice, a gas refund when signed by alice.
+    fn refund(
+        receipt_id: CryptoHash,
+        signer_id: &str,
+        deposit: u128,
+    ) -> IndexerExecutionOutcomeWithReceipt {
+        let mut receipt = receipt(receipt_id, "system", "alice.near");
+        if let ReceiptEnumView::Action {
+            signer_id: receipt_signer_id,
+            actions,
+            ..
+        } = &mut receipt.receipt
+        {
+            *receipt_signer_id = signer_id.parse().unwrap();
+            actions.push(ActionView::Transfer { deposit });
+        }
+        receipt_outcome(receipt, &[])
+    }
+
+    /// Resolves the lineage of every block before tracing it, as the indexer does.
+    struct Tracer {
+        lineage_store: Arc<RwLock<LineageStore>>,
+        trace_tracker: TraceTracker,
+    }
+
+    impl Tracer {
+        fn new(trace_tracker: TraceTracker) -> Self {
+            Self {
+                lineage_store: Arc::new(RwLock::new(LineageStore::open(None).unwrap())),
+                trace_tracker,
+            }
+        }
+
+        fn handle_block(
+            &mut self,
+            block_height: u64,
+            transactions: Vec<IndexerTransactionWithOutcome>,
+            outcomes: Vec<IndexerExecutionOutcomeWithReceipt>,
+        ) -> (
+            Vec<ReceiptTrace>,
+            Vec<TransactionSummary>,
+            Vec<TransactionFee>,
+        ) {
+            let msg = streamer_message(
+                block_height,
+                vec![shard(block_height, 0, transactions, outcomes)],
+            );
+            resolve_block_lineage(
+                &msg,
+                &Default::default(),
+                &mut self.lineage_store.write(),
+                &Default::default(),
+                &Default::default(),
+            )
+            .unwrap();
+            handle_shard_receipts(&msg, &self.lineage_store).unwrap();
+            self.lineage_store.write().evict(block_height).unwrap();
+            self.trace_tracker.handle_block(&msg)
+        }
+    }
+
+    #[test]
+    fn trace_completes_with_depths_and_totals() {
+        let tx_hash = hash("transaction");
+        let [r0, r1, r2, r3] = ["r0", "r1", "r2", "r3"].map(hash);
+        let mut tracer = Tracer::new(TraceTracker::new());
+        let mut receipt_traces = vec![];
+
+        let blocks = [
+            (vec![signed(tx_hash, r0)], vec![]),
+            (vec![], vec![executed(r0, &[r1, r2], 10)]),
+            (vec![], vec![executed(r1, &[r3], 20), executed(r2, &[], 30)]),
+        ];
+        for (block_height, (transactions, outcomes)) in (1..).zip(blocks) {
+            let (traces, summaries, _) = tracer.handle_block(block_height, transactions, outcomes);
+            receipt_traces.extend(traces);
+            assert!(summaries.is_empty());
+        }
+        let (traces, summaries, fees) = tracer.handle_block(4, vec![], vec![executed(r3, &[], 40)]);
+        assert!(traces.is_empty());
+
+        let receipt_traces: Vec<_> = receipt_traces
+            .into_iter()
+            .map(|trace| (trace.receipt_id, trace.parent_receipt_id, trace.depth))
+            .collect();
+        assert_eq!(
+            receipt_traces,
+            [
+                (r0.to_string(), None, 0),
+                (r1.to_string(), Some(r0.to_string()), 1),
+                (r2.to_string(), Some(r0.to_string()), 1),
+                (r3.to_string(), Some(r1.to_string()), 2),
+            ]
+        );
+        let [summary] = &summaries[..] else {
+            panic!("expected one summary, got {}", summaries.len());
+        };
+        assert_eq!(summary.total_receipts, 4);
+        assert_eq!(summary.total_gas_burnt, "105");
+        assert_eq!(summary.total_tokens_burnt, "10500");
+        assert!(summary.success);
+        assert_eq!(summary.completed_block_height, "4");
+        assert_eq!(fees[0].conversion_tokens_burnt, "500");
+        assert_eq!(fees[0].receipt_tokens_burnt, "10000");
+        assert_eq!(fees[0].fee_paid, "10500");
+        assert_eq!(fees[0].gas_refunded, "0");
+        assert!(tracer.trace_tracker.transactions.is_empty());
+        assert!(tracer.trace_tracker.pending_receipts.is_empty());
+    }
+
+    #[test]
+    fn trace_fails_when_any_receipt_fails() {
+        let tx_hash = hash("transaction");
+        let [r0, r1, refund] = ["r0", "r1", "refund"].map(hash);
+        let mut tracer = Tracer::new(TraceTracker::new());
+
+        tracer.handle_block(1, vec![signed(tx_hash, r0)], vec![]);
+        tracer.handle_block(2, vec![], vec![executed(r0, &[r1], 10)]);
+        let (_, summaries, _) =
+            tracer.handle_block(3, vec![], vec![failed(executed(r1, &[refund], 10))]);
+        assert!(summaries.is_empty());
+
+        let (_, summaries, _) = tracer.handle_block(4, vec![], vec![executed(refund, &[], 0)]);
+        assert_eq!(summaries.len(), 1);
+        assert!(!summaries[0].success);
+        assert_eq!(summaries[0].total_receipts, 3);
+    }
+
+    #[test]
+    fn trace_drops_expired_trees_and_counts_untraced_receipts() {
+        let tx_hash = hash("transaction");
+        let [r0, unknown] = ["r0", "unknown"].map(hash);
+        let mut tracer = Tracer::new(TraceTracker {
+            max_pending_blocks: 10,
+            ..TraceTracker::new()
+        });
+
+        tracer.handle_block(1, vec![signed(tx_hash, r0)], vec![]);
+        tracer.handle_block(11, vec![], vec![executed(unknown, &[], 0)]);
+        assert_eq!(tracer.trace_tracker.dropped(), (1, 0));
+
+        tracer.handle_block(12, vec![], vec![]);
+        assert_eq!(tracer.trace_tracker.dropped(), (1, 1));
+        assert!(tracer.trace_tracker.pending_receipts.is_empty());
+
+        let (_, summaries, _) = tracer.handle_block(13, vec![], vec![executed(r0, &[], 0)]);
+        assert!(summaries.is_empty());
+        assert_eq!(tracer.trace_tracker.dropped(), (2, 1));
+    }
+
+    #[test]
+    fn trace_outlives_lineage_eviction() {
+        let tx_hash = hash("transaction");
+        let [r0, r1] = ["r0", "r1"].map(hash);
+        let mut tracer = Tracer::new(TraceTracker::new());
+
+        tracer.handle_block(1, vec![signed(tx_hash, r0)], vec![]);
+        tracer.handle_block(2, vec![], vec![executed(r0, &[r1], 10)]);
+        for block_height in 3..30 {
+            tracer.handle_block(block_height, vec![], vec![]);
+        }
+        assert_eq!(tracer.lineage_store.read().get_receipt(&r1).unwrap(), None);
+
+        let (_, summaries, fees) = tracer.handle_block(30, vec![], vec![executed(r1, &[], 20)]);
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].total_receipts, 2);
+        assert_eq!(fees.len(), 1);
+        assert_eq!(tracer.trace_tracker.dropped(), (0, 0));
+    }
+
+    #[test]
+    fn system_transfers_are_classified_by_parent() {
+        let tx_hash = hash("transaction");
+        let [r0, failing, payout, gas_refund, deposit_refund] =
+            ["r0", "failing", "payout", "gas refund", "deposit refund"].map(hash);
+        let mut tracer = Tracer::new(TraceTracker::new());
+
+        let mut delete_account = executed(r0, &[payout, gas_refund], 10);
+        if let ReceiptEnumView::Action { actions, .. } = &mut delete_account.receipt.receipt {
+            actions.push(ActionView::DeleteAccount {
+                beneficiary_id: "alice.near".parse().unwrap(),
+            });
+        }
+        tracer.handle_block(1, vec![signed(tx_hash, r0)], vec![]);
+        tracer.handle_block(
+            2,
+            vec![],
+            vec![
+                delete_account,
+                failed(executed(failing, &[deposit_refund], 10)),
+            ],
+        );
+
+        let mut gas_refund_receipt = receipt(gas_refund, "system", "alice.near");
+        if let ReceiptEnumView::Action { signer_id, .. } = &mut gas_refund_receipt.receipt {
+            *signer_id = "alice.near".parse().unwrap();
+        }
+        let system_transfers = [
+            receipt(payout, "system", "alice.near"),
+            gas_refund_receipt,
+            receipt(deposit_refund, "system", "alice.near"),
+            receipt(hash("untracked"), "system", "alice.near"),
+            receipt(hash("transfer"), "carol.near", "alice.near"),
+        ]
+        .map(|receipt| {
+            tracer
+                .trace_tracker
+                .system_transfer(&receipt)
+                .map(|(cause, account_id)| (cause.to_string(), account_id.to_string()))
+        });
+        assert_eq!(
+            system_transfers,
+            [
+                Some(("DELETE_ACCOUNT".to_string(), "bob.near".to_string())),
+                Some(("GAS_REFUND".to_string(), "system".to_string())),
+                Some(("DEPOSIT_REFUND".to_string(), "system".to_string())),
+                None,
+                None,
+            ]
+        );
+        assert_eq!(tracer.trace_tracker.parent_receipt_id(&payout), Some(r0));
+        assert_eq!(
+            tracer.trace_tracker.parent_receipt_id(&deposit_refund),
+            Some(failing)
+        );
+    }
+
+    #[test]
+    fn fee_counts_gas_refunds_only() {
+        let tx_hash = hash("transaction");
+        let [r0, gas_refund, deposit_refund] = ["r0", "gas refund", "deposit refund"].map(hash);
+        let mut tracer = Tracer::new(TraceTracker::new());
+
+        tracer.handle_block(1, vec![signed(tx_hash, r0)], vec![]);
+        tracer.handle_block(
+            2,
+            vec![],
+            vec![failed(executed(r0, &[deposit_refund, gas_refund], 10))],
+        );
+        let (_, _, fees) = tracer.handle_block(
+            3,
+            vec![],
+            vec![
+                refund(deposit_refund, "system", 1_000_000),
+                refund(gas_refund, "alice.near", 7),
+            ],
+        );
+
+        assert_eq!(fees.len(), 1);
+        assert_eq!(fees[0].fee_paid, "1500");
+        assert_eq!(fees[0].gas_refunded, "7");
+    }
+}
@@ -6,16 +6,26 @@ use itertools::Itertools;
 use near_lake_framework::near_indexer_primitives::types::AccountId;
 use qlytics_core::Result;
 use qlytics_graphql::{
-    add_block_data, add_genesis_block_data, delete_accounts, AddBlockData, AddGenesisBlockData,
-    BlockData, DeleteAccounts, GenesisBlockData,
+    add_block_data, add_genesis_block_data, delete_accounts, update_receipt_lineages, AddBlockData,
+    AddGenesisBlockData, BlockData, DeleteAccounts, GenesisBlockData, ReceiptLineage,
+    UpdateReceiptLineages,
 };
 use reqwest::Client;
 use std::env;
 use tokio_stream::{Stream, StreamExt};
 
 pub async fn prepare_data(
-    stream: impl Stream<Item = Result<Either<GenesisBlockData, (BlockData, Vec<AccountId>)>>>,
-) -> impl Stream<Item = Result<(Vec<GenesisBlockData>, Vec<BlockData>, Vec<String>)>> {
+    stream: impl Stream<
+        Item = Result<Either<GenesisBlockData, (BlockData, Vec<AccountId>, Vec<ReceiptLineage>)>>,
+    >,
+) -> impl Stream<
+    Item = Result<(
+        Vec<GenesisBlockData>,
+        Vec<BlockData>,
+        Vec<String>,
+        Vec<ReceiptLineage>,
+    )>,
+> {
     try_stream! {
         let mut data = vec![];
         for await block_data in stream {
@@ -25,32 +35,47 @@ pub async fn prepare_data(
             }
             let (genesis_block_data, data): (Vec<_>, Vec<_>) =
                 data.drain(..).into_iter().partition_map(|val| val);
-            let (block_data, account_ids): (Vec<BlockData>, Vec<Vec<AccountId>>) =
-                data.into_iter().unzip();
+            let (block_data, account_ids, receipt_lineages): (
+                Vec<BlockData>,
+                Vec<Vec<AccountId>>,
+                Vec<Vec<ReceiptLineage>>,
+            ) = data.into_iter().multiunzip();
             let account_ids = account_ids
                 .into_iter()
                 .flatten()
                 .map(|account_id| account_id.to_string())
                 .collect();
 
-            yield (genesis_block_data, block_data, account_ids);
+            let receipt_lineages = receipt_lineages.into_iter().flatten().collect();
+
+            yield (genesis_block_data, block_data, account_ids, receipt_lineages);
         }
     }
 }
 
 pub async fn send_data(
-    stream: impl Stream<Item = Result<(Vec<GenesisBlockData>, Vec<BlockData>, Vec<String>)>>,
+    stream: impl Stream<
+        Item = Result<(
+            Vec<GenesisBlockData>,
+            Vec<BlockData>,
+            Vec<String>,
+            Vec<ReceiptLineage>,
+        )>,
+    >,
 ) {
     pin_mut!(stream);
 
     while let Some(data) = stream.next().await {
-        let (genesis_block_data, block_data, account_ids) = data.unwrap();
+        let (genesis_block_data, block_data, account_ids, receipt_lineages) = data.unwrap();
         let client = Client::new();
         send_genesis_block_data(&client, genesis_block_data)
             .await
             .unwrap();
         send_block_data(&client, block_data).await.unwrap();
         send_deleted_accounts(&client, account_ids).await.unwrap();
+        send_receipt_lineages(&client, receipt_lineages)
+            .await
+            .unwrap();
     }
 }
 
@@ -107,3 +132,20 @@ pub async fn send_deleted_accounts(client: &Client, account_ids: Vec<String>) ->
         .await?;
     Ok(())
 }
+
+pub async fn send_receipt_lineages(
+    client: &Client,
+    receipt_lineages: Vec<ReceiptLineage>,
+) -> Result<()> {
+    if receipt_lineages.is_empty() {
+        return Ok(());
+    }
+    let variables = update_receipt_lineages::Variables { receipt_lineages };
+    let query = UpdateReceiptLineages::build_query(variables);
+    client
+        .post(env::var("API_URL").unwrap())
+        .json(&query)
+        .send()
+        .await?;
+    Ok(())
+}
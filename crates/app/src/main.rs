@@ -1,8 +1,22 @@
 use qlytics_core::Result;
+use reqwest::Client;
+use std::env;
 
 #[tokio::main]
 pub async fn main() -> Result<()> {
     dotenv::dotenv().ok();
+    if env::args().nth(1).as_deref() == Some("backfill-lineage") {
+        let receipt_lineages = qlytics_indexer::backfill_lineage().await?;
+        println!("Resolved {} receipts", receipt_lineages.len());
+        let receipt_ids: Vec<_> = receipt_lineages
+            .iter()
+            .map(|receipt_lineage| receipt_lineage.receipt_id.clone())
+            .collect();
+        qlytics_send::send_receipt_lineages(&Client::new(), receipt_lineages).await?;
+        qlytics_indexer::forget_resolved_receipts(&receipt_ids)?;
+        return Ok(());
+    }
+
     let stream = qlytics_indexer::start_indexing().await?;
     let stream = qlytics_send::prepare_data(stream).await;
     qlytics_send::send_data(stream).await;